@@ -0,0 +1,102 @@
+use crate::config::sanitize_name;
+use crate::err::{Error, Result};
+use std::path::{Path, PathBuf};
+use std::{env, fs, io};
+
+/// Name of the file listing the tasks that can be approved.
+const MANUAL_TASKS: &str = "manual-tasks";
+
+/// Approvals of the `manual` tasks of a run.
+///
+/// The approvals are marker files in `$TMPDIR/runr-approvals/<run-id>/`, written by
+/// [approve] and watched by the run. The directory exists only while the run is active.
+#[derive(Debug)]
+pub struct Approvals {
+    dir: PathBuf,
+    task_names: Vec<String>,
+}
+
+impl Approvals {
+    /// Set up the directory for the approvals of the given tasks.
+    pub fn create(run_id: &str, task_names: &[&str]) -> Result<Self> {
+        let dir = approvals_dir(run_id);
+        fs::create_dir_all(&dir)?;
+        fs::write(dir.join(MANUAL_TASKS), task_names.join("\n"))?;
+        let task_names = task_names.iter().map(|n| n.to_string()).collect();
+        Ok(Self { dir, task_names })
+    }
+
+    /// Add tasks that can be approved, eg. ones generated by a task.
+    pub fn add(&mut self, task_names: &[&str]) -> Result<()> {
+        self.task_names
+            .extend(task_names.iter().map(|n| n.to_string()));
+        fs::write(self.dir.join(MANUAL_TASKS), self.task_names.join("\n"))?;
+        Ok(())
+    }
+
+    pub fn is_approved(&self, task_name: &str) -> bool {
+        self.task_names
+            .iter()
+            .position(|n| n == task_name)
+            .is_some_and(|i| marker(&self.dir, i).exists())
+    }
+
+    /// Remove the directory, after which the tasks can no longer be approved.
+    pub fn remove(self) -> Result<()> {
+        Ok(fs::remove_dir_all(self.dir)?)
+    }
+}
+
+/// Approve the `manual` task `task_name` of the active run `run_id`.
+pub fn approve(run_id: &str, task_name: &str) -> Result<()> {
+    let dir = approvals_dir(run_id);
+    let task_names = match fs::read_to_string(dir.join(MANUAL_TASKS)) {
+        Ok(names) => names,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => {
+            return Err(Error::Approval(format!("no active run '{run_id}'")));
+        }
+        Err(e) => return Err(e.into()),
+    };
+    let Some(ind) = task_names.lines().position(|n| n == task_name) else {
+        let names: Vec<_> = task_names.lines().map(|n| format!("'{n}'")).collect();
+        return Err(Error::Approval(format!(
+            "'{task_name}' is not a manual task of '{run_id}', expected one of {}",
+            names.join(", ")
+        )));
+    };
+    fs::write(marker(&dir, ind), "")?;
+    Ok(())
+}
+
+/// Task names are not valid filenames, so the markers are named by the index of the task.
+fn marker(dir: &Path, ind: usize) -> PathBuf {
+    dir.join(format!("approved-{ind}"))
+}
+
+fn approvals_dir(run_id: &str) -> PathBuf {
+    env::temp_dir()
+        .join("runr-approvals")
+        .join(sanitize_name(run_id))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn tasks_are_approved_with_markers() {
+        let run_id = "runr-approval-test-1";
+        let approvals = Approvals::create(run_id, &["deploy (env=prod)", "release"]).unwrap();
+        assert!(!approvals.is_approved("deploy (env=prod)"));
+        approve(run_id, "deploy (env=prod)").unwrap();
+        assert!(approvals.is_approved("deploy (env=prod)"));
+        assert!(!approvals.is_approved("release"));
+        assert!(matches!(approve(run_id, "build"), Err(Error::Approval(_))));
+
+        approvals.remove().unwrap();
+        assert!(matches!(
+            approve(run_id, "release"),
+            Err(Error::Approval(_))
+        ));
+    }
+}
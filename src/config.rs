@@ -1,9 +1,10 @@
 use crate::err::Result;
 use crate::pipeline::Pipeline;
+use std::collections::BTreeMap;
 use std::path::{Path, PathBuf};
 use std::process::Command;
 use std::time::{SystemTime, UNIX_EPOCH};
-use std::{env, fs, io};
+use std::{env, fmt, fs, io};
 
 const YAML: &str = "runr.yaml";
 
@@ -16,6 +17,9 @@ pub struct Config {
     pipeline_filename: String,
     timestamp: u64,
     cleanup: bool,
+    old_rev: Option<String>,
+    new_rev: Option<String>,
+    pipeline: Option<String>,
 }
 
 impl Config {
@@ -23,11 +27,14 @@ impl Config {
     ///
     /// Relevant variables are
     /// * `BARE_PATH`: path to the bare repository, defaults to [env::current_dir()]
-    /// * `BRANCH`: *WARNING*: panics if unset
+    /// * `BRANCH`: *WARNING*: panics if unset, tags are given as `refs/tags/<tag>`
     /// * `DEFAULT_IMAGE`: default image to be used if the image is unset, defaults to
     ///   debian:bookworm
     /// * `PIPELINE_FILENAME`: filename for the pipeline definition, defaults to `runr.yaml`
     /// * `CLEANUP`: should the containers and directories be removed, defaults to `true`
+    /// * `OLD_REV` and `NEW_REV`: the revisions before and after the push, used for
+    ///   determining the changed files (see [Self::changed_files])
+    /// * `PIPELINE`: name of the pipeline to run, if the file defines `pipelines`
     pub fn from_env() -> Self {
         let bare_path: PathBuf = match env::var("BARE_PATH") {
             Ok(p) => p.parse().expect("Invalid value for BARE_PATH"),
@@ -53,6 +60,17 @@ impl Config {
             pipeline_filename: env::var("PIPELINE_FILENAME").unwrap_or(YAML.to_string()),
             timestamp,
             cleanup,
+            old_rev: env::var("OLD_REV").ok().filter(|r| !r.is_empty()),
+            new_rev: env::var("NEW_REV").ok().filter(|r| !r.is_empty()),
+            pipeline: env::var("PIPELINE").ok().filter(|p| !p.is_empty()),
+        }
+    }
+
+    /// Run the given named pipeline regardless of the ref, overrides `PIPELINE`.
+    pub fn with_pipeline(self, pipeline: String) -> Self {
+        Self {
+            pipeline: Some(pipeline),
+            ..self
         }
     }
 
@@ -75,17 +93,107 @@ impl Config {
         &self.default_image
     }
 
-    pub fn run_config(&self, pipeline: &Pipeline) -> RunConfig {
-        let container_name_prefix = format!(
-            "runr-{}-{}-{}",
-            self.repo_name, self.repo_branch, self.timestamp
+    /// The pushed ref, ie. either a branch or a tag.
+    pub fn git_ref(&self) -> GitRef {
+        GitRef::from(self.repo_branch.as_str())
+    }
+
+    pub fn pipeline_config(&self) -> PipelineConfig {
+        let config = PipelineConfig::new(
+            self.default_image.clone(),
+            self.git_ref(),
+            self.repo_path(),
+            self.pipeline_filename.clone(),
+            self.builtin_vars(),
+            self.changed_files(),
         );
-        RunConfig::new(
+        match &self.pipeline {
+            Some(pipeline) => config.with_pipeline(pipeline.clone()),
+            None => config,
+        }
+    }
+
+    /// Files changed by the push, ie. between `OLD_REV` and `NEW_REV`.
+    ///
+    /// `None` (ie. all the files are considered changed) if
+    /// * the revisions are not given
+    /// * the push created a new branch (or a tag), ie. `OLD_REV` is all zeros
+    /// * the push was forced, ie. `OLD_REV` is not an ancestor of `NEW_REV`
+    /// * the changes cannot be resolved, eg. before [repo_checkout].
+    pub fn changed_files(&self) -> Option<Vec<String>> {
+        let (old_rev, new_rev) = (self.old_rev.as_deref()?, self.new_rev.as_deref()?);
+        if old_rev.chars().all(|c| c == '0') {
+            println!("New ref, considering all files changed");
+            return None;
+        }
+        let git = |args: &[&str]| {
+            Command::new("git")
+                .current_dir(self.repo_path())
+                .env_remove("GIT_DIR")
+                .args(args)
+                .output()
+                .ok()
+                .filter(|o| o.status.success())
+        };
+        if git(&["merge-base", "--is-ancestor", old_rev, new_rev]).is_none() {
+            println!("{old_rev} is not an ancestor of {new_rev}, considering all files changed");
+            return None;
+        }
+        let output = git(&["diff", "--name-only", "--no-renames", old_rev, new_rev])?;
+        let files = String::from_utf8_lossy(&output.stdout);
+        Some(files.lines().map(String::from).collect())
+    }
+
+    /// Variables describing the run that can be used in the pipeline as `${runr.<name>}`:
+    /// `ref` (branch or tag), `branch` or `tag`, `repo`, `timestamp` and `commit`
+    /// (which is available only after [repo_checkout]).
+    pub fn builtin_vars(&self) -> BTreeMap<String, String> {
+        let mut vars = BTreeMap::new();
+        let (kind, name) = match self.git_ref() {
+            GitRef::Branch(b) => ("branch", b),
+            GitRef::Tag(t) => ("tag", t),
+        };
+        vars.insert(format!("runr.{kind}"), name.clone());
+        vars.insert(String::from("runr.ref"), name);
+        vars.insert(String::from("runr.repo"), self.repo_name.clone());
+        vars.insert(String::from("runr.timestamp"), self.timestamp.to_string());
+        if let Ok(commit) = self.commit() {
+            vars.insert(String::from("runr.commit"), commit);
+        }
+        vars
+    }
+
+    /// Commit that is checked out.
+    pub fn commit(&self) -> Result<String> {
+        let output = Command::new("git")
+            .current_dir(self.repo_path())
+            .env_remove("GIT_DIR")
+            .args(["rev-parse", "HEAD"])
+            .output()?;
+        if !output.status.success() {
+            Err(io::Error::other("unable to resolve commit"))?
+        }
+        Ok(String::from_utf8_lossy(&output.stdout).trim().to_string())
+    }
+
+    /// Must be called after [repo_checkout] as the commit is resolved from the checkout.
+    pub fn run_config(&self, pipeline: &Pipeline) -> Result<RunConfig> {
+        Ok(RunConfig::new(
             self.repo_path(),
-            container_name_prefix,
+            self.repo_name.clone(),
+            self.run_id(),
             self.cleanup,
             pipeline.name_width(),
-        )
+            self.git_ref(),
+            self.commit()?,
+        ))
+    }
+
+    /// Identifier of the run, used eg. for approving manual tasks.
+    pub fn run_id(&self) -> String {
+        // container names cannot contain slashes (eg. release/1.0 or refs/tags/v1)
+        let branch = self.repo_branch.replace('/', "-");
+        format!("{}-{branch}-{}", self.repo_name, self.timestamp)
     }
 
     /// Remove the cloned repository.
@@ -117,44 +225,176 @@ pub fn repo_checkout(config: &Config) -> Result<()> {
     Ok(())
 }
 
+/// Either a branch or a tag.
+#[derive(Clone, Debug, PartialEq)]
+pub enum GitRef {
+    Branch(String),
+    Tag(String),
+}
+
+impl From<&str> for GitRef {
+    /// Parse `refs/tags/<tag>` as a tag and anything else as a branch
+    /// (with the optional `refs/heads/` prefix removed).
+    fn from(git_ref: &str) -> Self {
+        if let Some(tag) = git_ref.strip_prefix("refs/tags/") {
+            return Self::Tag(tag.to_string());
+        }
+        let branch = git_ref.strip_prefix("refs/heads/").unwrap_or(git_ref);
+        Self::Branch(branch.to_string())
+    }
+}
+
+impl fmt::Display for GitRef {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            GitRef::Branch(b) => write!(f, "branch '{b}'"),
+            GitRef::Tag(t) => write!(f, "tag '{t}'"),
+        }
+    }
+}
+
+/// Part of the configuration that is relevant when reading the pipeline.
+#[derive(Clone, Debug, PartialEq)]
+pub struct PipelineConfig {
+    default_image: Option<String>,
+    git_ref: GitRef,
+    repo_path: PathBuf,
+    pipeline_filename: String,
+    builtin_vars: BTreeMap<String, String>,
+    changed_files: Option<Vec<String>>,
+    pipeline: Option<String>,
+}
+
+impl PipelineConfig {
+    pub fn new(
+        default_image: Option<String>,
+        git_ref: GitRef,
+        repo_path: PathBuf,
+        pipeline_filename: String,
+        builtin_vars: BTreeMap<String, String>,
+        changed_files: Option<Vec<String>>,
+    ) -> Self {
+        Self {
+            default_image,
+            git_ref,
+            repo_path,
+            pipeline_filename,
+            builtin_vars,
+            changed_files,
+            pipeline: None,
+        }
+    }
+
+    /// Select the named pipeline explicitly instead of with the rules.
+    pub fn with_pipeline(self, pipeline: String) -> Self {
+        Self {
+            pipeline: Some(pipeline),
+            ..self
+        }
+    }
+
+    /// Name of the pipeline that is selected explicitly, see [Self::with_pipeline].
+    pub fn pipeline(&self) -> Option<&str> {
+        self.pipeline.as_deref()
+    }
+
+    /// See [Config::changed_files].
+    pub fn changed_files(&self) -> Option<&[String]> {
+        self.changed_files.as_deref()
+    }
+
+    /// See [Config::builtin_vars].
+    pub fn builtin_vars(&self) -> &BTreeMap<String, String> {
+        &self.builtin_vars
+    }
+
+    pub fn default_image(&self) -> Option<&str> {
+        self.default_image.as_deref()
+    }
+
+    pub fn git_ref(&self) -> &GitRef {
+        &self.git_ref
+    }
+
+    pub fn repo_path(&self) -> &Path {
+        &self.repo_path
+    }
+
+    /// Filename of the pipeline definition, relative to the repository root.
+    pub fn pipeline_filename(&self) -> &str {
+        &self.pipeline_filename
+    }
+
+    /// Same configuration for reading another pipeline definition, eg. one generated by a task.
+    pub fn with_pipeline_filename(&self, pipeline_filename: String) -> Self {
+        Self {
+            pipeline_filename,
+            ..self.clone()
+        }
+    }
+}
+
 /// Part of the configuration that is relevant during runtime.
 #[derive(Debug)]
 pub struct RunConfig {
     repo_path: PathBuf,
-    container_name_prefix: String,
+    repo_name: String,
+    run_id: String,
     cleanup: bool,
     task_name_width: usize,
+    git_ref: GitRef,
+    commit: String,
 }
 
 impl RunConfig {
     pub fn new(
         repo_path: PathBuf,
-        container_name_prefix: String,
+        repo_name: String,
+        run_id: String,
         cleanup: bool,
         task_name_width: usize,
+        git_ref: GitRef,
+        commit: String,
     ) -> Self {
         Self {
             repo_path,
-            container_name_prefix,
+            repo_name,
+            run_id,
             cleanup,
             task_name_width,
+            git_ref,
+            commit,
         }
     }
 
+    pub fn git_ref(&self) -> &GitRef {
+        &self.git_ref
+    }
+
+    pub fn commit(&self) -> &str {
+        &self.commit
+    }
+
     pub fn cleanup(&self) -> bool {
         self.cleanup
     }
 
+    pub fn repo_name(&self) -> &str {
+        &self.repo_name
+    }
+
+    /// See [Config::run_id].
+    pub fn run_id(&self) -> &str {
+        &self.run_id
+    }
+
     pub fn mk_container_name(&self, task_name: &str) -> String {
         let Ok(ts) = SystemTime::now().duration_since(UNIX_EPOCH) else {
             // TODO
             todo!()
         };
-        format!(
-            "{}-{task_name}-{}",
-            self.container_name_prefix,
-            ts.as_secs()
-        )
+        let task_name = sanitize_name(task_name);
+        format!("runr-{}-{task_name}-{}", self.run_id, ts.as_secs())
     }
 
     pub fn repo_path(&self) -> &Path {
@@ -165,3 +405,27 @@ impl RunConfig {
         self.task_name_width
     }
 }
+
+/// Replace characters other than `[a-zA-Z0-9_.-]`, which are not allowed eg. in
+/// container names (matrix tasks contain spaces).
+pub fn sanitize_name(name: &str) -> String {
+    name.chars()
+        .map(|c| match c.is_ascii_alphanumeric() || "_.-".contains(c) {
+            true => c,
+            false => '_',
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn git_ref_is_parsed() {
+        let branch = |b: &str| GitRef::Branch(b.to_string());
+        assert_eq!(GitRef::from("main"), branch("main"));
+        assert_eq!(GitRef::from("refs/heads/release/1"), branch("release/1"));
+        assert_eq!(GitRef::from("refs/tags/v1.0"), GitRef::Tag("v1.0".into()));
+    }
+}
@@ -1,6 +1,9 @@
 use crate::config::RunConfig;
 use crate::err::Result;
+use crate::pipeline::cpus_arg;
+use std::collections::BTreeMap;
 use std::io::{PipeWriter, Write};
+use std::path::Path;
 use std::process::{Child, Command, Stdio};
 
 /// Commands that are run using `podman`.
@@ -10,6 +13,13 @@ pub enum ContainerCommand<'a> {
     Run {
         commands: &'a str,
         image: &'a str,
+        shell: &'a [&'a str],
+        working_dir: Option<&'a Path>,
+        env: &'a BTreeMap<String, String>,
+        /// Limit of the CPUs in thousandths of a CPU.
+        millicpus: Option<u64>,
+        /// Limit of the memory in bytes.
+        memory: Option<u64>,
         container_name: &'a str,
         config: &'a RunConfig,
     },
@@ -26,9 +36,32 @@ impl<'a> ContainerCommand<'a> {
                 commands,
                 config,
                 image,
+                shell,
+                working_dir,
+                env,
+                millicpus,
+                memory,
                 container_name,
             } => {
-                let mut child = spawn_container(container_name, image, config, output)?;
+                let mut args: Vec<_> = env
+                    .iter()
+                    .flat_map(|(k, v)| ["--env".to_string(), format!("{k}={v}")])
+                    .collect();
+                if let Some(millicpus) = millicpus {
+                    args.push(format!("--cpus={}", cpus_arg(millicpus)));
+                }
+                if let Some(memory) = memory {
+                    args.push(format!("--memory={memory}b"));
+                }
+                let mut child = spawn_container(
+                    container_name,
+                    image,
+                    shell,
+                    working_dir,
+                    &args,
+                    config,
+                    output,
+                )?;
                 write!(child.stdin.take().expect("run stdin taken"), "{commands}")?;
                 Ok(child)
             }
@@ -49,6 +82,9 @@ impl<'a> ContainerCommand<'a> {
 fn spawn_container(
     name: &str,
     image_name: &str,
+    shell: &[&str],
+    working_dir: Option<&Path>,
+    args: &[String],
     config: &RunConfig,
     output: PipeWriter,
 ) -> Result<Child> {
@@ -56,13 +92,17 @@ fn spawn_container(
         true => ["run", "--rm", "--interactive", "--userns", "keep-id"].as_slice(),
         false => ["run", "--interactive", "--userns", "keep-id"].as_slice(),
     };
-    let workdir = "/__repo";
+    let mount_path = Path::new("/__repo");
     let repo_path = config.repo_path().to_str().expect("invalid repo path");
-    let volume = format!("{repo_path}:{workdir}");
+    let volume = format!("{repo_path}:{}", mount_path.display());
+    let workdir = mount_path.join(working_dir.unwrap_or(Path::new("")));
+    let workdir = workdir.to_str().expect("invalid working dir");
     Ok(Command::new("podman")
         .args(run_args)
         .args(["--name", name, "--volume", &volume, "--workdir", workdir])
-        .args([image_name, "/bin/bash"])
+        .args(args)
+        .arg(image_name)
+        .args(shell)
         .stdout(output.try_clone()?)
         .stderr(output)
         .stdin(Stdio::piped())
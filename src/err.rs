@@ -1,15 +1,47 @@
 use crate::pipeline::TaskId;
+use crate::suggest::closest;
 use crate::worker::WorkInput;
+use std::time::Duration;
 use std::{error, fmt, io, num, sync::mpsc};
 
 #[derive(Debug)]
 pub enum Error {
+    Approval(String),
+    /// Names of the tasks on the cycle, starting and ending with the same task.
     DependencyCycle(Vec<String>),
-    DuplicateTask(String),
+    DuplicateTask(String, Vec<String>),
+    DuplicateTemplate(String),
     FailedTask(TaskId, String),
+    FinalizerDependency(String, String),
+    InvalidCommands(String, String),
+    InvalidCondition(String, String),
+    InvalidEnvName(String),
+    InvalidGenerates(String, String),
+    InvalidInclude(String, String),
+    InvalidMatrix(String, String),
+    /// All the errors found when validating the pipeline.
+    InvalidPipeline(Vec<Error>),
+    InvalidPipelines(String),
+    InvalidResources(String, String),
+    InvalidRetryDelay(String, String),
+    InvalidShell(String),
+    InvalidTimeout(String, String),
+    InvalidVariable(String, String),
+    InvalidWorkingDir(String, String),
     Io(io::Error),
+    /// Error at the location, eg. `runr.yaml:3:5`.
+    Located(String, Box<Error>),
+    TemplateCycle(Vec<String>),
+    TimedOut(Duration),
     TooManyTasks(usize),
+    /// Task, the undefined dependency and the closest task name, if any.
+    UndefinedDependency(String, String, Option<String>),
+    UndefinedStage(String, String),
+    UndefinedPipeline(String),
     UndefinedTask(String),
+    UndefinedTemplate(String),
+    /// Key that is not defined for the context, the expected keys and the location.
+    UnknownKey(String, Vec<String>, String),
     Worker(String),
 }
 
@@ -18,15 +50,63 @@ pub type Result<T> = std::result::Result<T, Error>;
 impl fmt::Display for Error {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         match self {
-            Error::DependencyCycle(tasks) => {
-                write!(f, "Dependency cycle containing the following tasks:")?;
-                tasks.iter().try_for_each(|t| write!(f, "\n  - {t}"))
+            Error::Approval(e) => write!(f, "Unable to approve: {e}"),
+            Error::DependencyCycle(tasks) => write!(f, "Dependency cycle: {}", tasks.join(" -> ")),
+            Error::DuplicateTask(n, sources) => {
+                write!(f, "Task {n} defined multiple times:")?;
+                sources.iter().try_for_each(|s| write!(f, "\n  - in {s}"))
             }
-            Error::DuplicateTask(n) => write!(f, "Task {n} defined multiple times"),
+            Error::DuplicateTemplate(n) => write!(f, "Template {n} defined multiple times"),
             Error::FailedTask(i, e) => write!(f, "Task [{i}] failed:\n{e}"),
+            Error::FinalizerDependency(t, d) => write!(
+                f,
+                "Task {t} cannot depend on {d}, which is run after all the other tasks"
+            ),
+            Error::InvalidCommands(t, e) => write!(f, "Invalid commands for task {t}: {e}"),
+            Error::InvalidCondition(t, e) => write!(f, "Invalid condition for task {t}: {e}"),
+            Error::InvalidEnvName(n) => write!(f, "Invalid environment variable name '{n}'"),
+            Error::InvalidGenerates(t, e) => write!(f, "Invalid generates for task {t}: {e}"),
+            Error::InvalidInclude(p, e) => write!(f, "Invalid include '{p}': {e}"),
+            Error::InvalidMatrix(t, e) => write!(f, "Invalid matrix for task {t}: {e}"),
+            Error::InvalidPipeline(errors) => {
+                write!(f, "Invalid pipeline, {} errors:", errors.len())?;
+                errors
+                    .iter()
+                    .try_for_each(|e| write!(f, "\n  - {}", e.to_string().replace('\n', "\n    ")))
+            }
+            Error::InvalidPipelines(e) => write!(f, "Invalid pipelines: {e}"),
+            Error::InvalidResources(t, e) => write!(f, "Invalid resources for {t}: {e}"),
+            Error::InvalidRetryDelay(t, e) => write!(f, "Invalid retry delay for {t}: {e}"),
+            Error::InvalidShell(t) => write!(f, "Empty shell for task {t}"),
+            Error::InvalidTimeout(t, e) => write!(f, "Invalid timeout for {t}: {e}"),
+            Error::InvalidVariable(t, e) => write!(f, "Invalid variable in {t}: {e}"),
+            Error::InvalidWorkingDir(t, e) => write!(f, "Invalid working_dir for task {t}: {e}"),
             Error::Io(e) => write!(f, "{e}"),
+            Error::Located(at, e) => write!(f, "{at}: {e}"),
+            Error::TemplateCycle(names) => {
+                write!(f, "Template cycle: {}", names.join(" -> "))
+            }
+            Error::TimedOut(d) => write!(f, "Timed out after {d:?}"),
             Error::TooManyTasks(n) => write!(f, "Too many ({n} > 255) tasks + images"),
+            Error::UndefinedDependency(t, d, closest) => {
+                write!(f, "Task {t} depends on undefined task '{d}'")?;
+                match closest {
+                    Some(name) => write!(f, ", did you mean '{name}'?"),
+                    None => Ok(()),
+                }
+            }
+            Error::UndefinedStage(t, s) => write!(f, "Undefined stage '{s}' for task {t}"),
+            Error::UndefinedPipeline(n) => write!(f, "Undefined pipeline name '{n}'"),
             Error::UndefinedTask(tn) => write!(f, "Undefined task name '{tn}'"),
+            Error::UndefinedTemplate(n) => write!(f, "Undefined template name '{n}'"),
+            Error::UnknownKey(k, expected, at) => {
+                write!(f, "Unknown key '{k}'{at}")?;
+                let expected: Vec<_> = expected.iter().map(String::as_str).collect();
+                match closest(k, expected.iter().copied()) {
+                    Some(key) => write!(f, ", did you mean '{key}'?"),
+                    None => write!(f, ", expected one of '{}'", expected.join("', '")),
+                }
+            }
             Error::Worker(e) => write!(f, "{e}"),
         }
     }
@@ -34,6 +114,17 @@ impl fmt::Display for Error {
 
 impl error::Error for Error {}
 
+impl Error {
+    /// Combine the errors into one, [Error::InvalidPipeline] if there are several of them.
+    pub fn collect(mut errors: Vec<Error>) -> Result<()> {
+        match errors.len() {
+            0 => Ok(()),
+            1 => Err(errors.remove(0)),
+            _ => Err(Error::InvalidPipeline(errors)),
+        }
+    }
+}
+
 impl From<io::Error> for Error {
     fn from(value: io::Error) -> Self {
         Self::Io(value)
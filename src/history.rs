@@ -0,0 +1,107 @@
+use crate::config::sanitize_name;
+use crate::err::Result;
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+use std::{env, fs, io};
+
+/// Durations of the tasks of the earlier runs of a repository, used for scheduling
+/// the tasks on the critical path first (see [crate::status::Status::next_runnable_if]).
+///
+/// The durations are stored by the task name in `$TMPDIR/runr-durations/<repo>.json`.
+#[derive(Debug)]
+pub struct History {
+    path: PathBuf,
+    durations: BTreeMap<String, u64>,
+    /// Durations of this run, in milliseconds.
+    recorded: BTreeMap<String, u64>,
+}
+
+impl History {
+    /// Read the durations of the repository, the history is empty if they cannot be read.
+    pub fn load(repo_name: &str) -> Self {
+        let path = env::temp_dir()
+            .join("runr-durations")
+            .join(format!("{}.json", sanitize_name(repo_name)));
+        Self {
+            durations: read_durations(&path).unwrap_or_default(),
+            path,
+            recorded: BTreeMap::new(),
+        }
+    }
+
+    /// Duration of the task in the latest run it succeeded in.
+    pub fn get(&self, task_name: &str) -> Option<Duration> {
+        self.durations
+            .get(task_name)
+            .map(|ms| Duration::from_millis(*ms))
+    }
+
+    /// Mean duration of the tasks, if any are known.
+    pub fn mean(&self) -> Option<Duration> {
+        let n = u32::try_from(self.durations.len())
+            .ok()
+            .filter(|n| *n > 0)?;
+        let total: u64 = self.durations.values().sum();
+        Some(Duration::from_millis(total) / n)
+    }
+
+    pub fn record(&mut self, task_name: &str, duration: Duration) {
+        let ms = u64::try_from(duration.as_millis()).unwrap_or(u64::MAX);
+        self.recorded.insert(task_name.to_string(), ms);
+    }
+
+    /// Write the recorded durations, merged into the ones written meanwhile by other runs.
+    pub fn save(self) -> Result<()> {
+        if self.recorded.is_empty() {
+            return Ok(());
+        }
+        let mut durations = read_durations(&self.path).unwrap_or_default();
+        durations.extend(self.recorded);
+        let dir = self.path.parent().expect("durations are in a directory");
+        fs::create_dir_all(dir)?;
+        // written as a whole, so that concurrent runs do not read partial files
+        let tmp = self
+            .path
+            .with_extension(format!("{}.tmp", std::process::id()));
+        fs::write(
+            &tmp,
+            serde_json::to_string(&durations).map_err(io::Error::other)?,
+        )?;
+        fs::rename(tmp, &self.path)?;
+        Ok(())
+    }
+}
+
+fn read_durations(path: &Path) -> Option<BTreeMap<String, u64>> {
+    let json = fs::read_to_string(path).ok()?;
+    serde_json::from_str(&json).ok()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn recorded_durations_are_merged() {
+        let repo = "runr-history-test";
+        let path = env::temp_dir()
+            .join("runr-durations")
+            .join(format!("{repo}.json"));
+        let _ = fs::remove_file(&path);
+        let mut history = History::load(repo);
+        assert_eq!(history.mean(), None);
+        history.record("build", Duration::from_secs(3));
+        let mut other = History::load(repo);
+        other.record("test", Duration::from_secs(1));
+        other.save().unwrap();
+        history.save().unwrap();
+
+        let history = History::load(repo);
+        assert_eq!(history.get("build"), Some(Duration::from_secs(3)));
+        assert_eq!(history.get("test"), Some(Duration::from_secs(1)));
+        assert_eq!(history.get("lint"), None);
+        assert_eq!(history.mean(), Some(Duration::from_secs(2)));
+        fs::remove_file(path).unwrap();
+    }
+}
@@ -1,11 +1,16 @@
+mod approval;
 mod config;
 mod container_command;
 mod err;
+mod history;
 mod pipeline;
 mod run;
 mod status;
+mod suggest;
 mod worker;
 
-pub use config::{Config, repo_checkout};
+pub use approval::approve;
+pub use config::{Config, GitRef, PipelineConfig, repo_checkout};
 pub use err::Result;
-pub use pipeline::{Pipeline, read_pipeline};
+pub use pipeline::{Pipeline, Reason, Selection, read_pipeline, schema};
+pub use run::CANCELLED;
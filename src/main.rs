@@ -1,10 +1,35 @@
-use runr::{Config, Result, read_pipeline, repo_checkout};
+use runr::{CANCELLED, Config, Result, approve, read_pipeline, repo_checkout, schema};
+use std::env;
+
+const USAGE: &str = "Usage: runr [--pipeline <name>] | runr approve <run-id> <task> | runr schema";
 
 fn main() {
-    let config = Config::from_env();
+    let args: Vec<_> = env::args().skip(1).collect();
+    let pipeline = match args.iter().map(String::as_str).collect::<Vec<_>>()[..] {
+        [] => None,
+        ["--pipeline", name] => Some(name.to_string()),
+        ["schema"] => {
+            println!("{}", schema());
+            std::process::exit(0)
+        }
+        ["approve", run_id, task] => match approve(run_id, task) {
+            Ok(()) => std::process::exit(0),
+            Err(e) => {
+                eprintln!("{e}");
+                std::process::exit(1)
+            }
+        },
+        _ => {
+            eprintln!("{USAGE}");
+            std::process::exit(2)
+        }
+    };
+    let config = match pipeline {
+        Some(name) => Config::from_env().with_pipeline(name),
+        None => Config::from_env(),
+    };
     let exit_code = match run(&config) {
-        Ok(true) => 0,
-        Ok(false) => 1,
+        Ok(exit_code) => exit_code,
         Err(e) => {
             eprintln!("{e}");
             1
@@ -16,14 +41,24 @@ fn main() {
     std::process::exit(exit_code)
 }
 
-fn run(config: &Config) -> Result<bool> {
+/// Exit code when the pipeline (or a task) timed out, same as with timeout(1).
+const TIMED_OUT: i32 = 124;
+
+fn run(config: &Config) -> Result<i32> {
     repo_checkout(config)?;
     let pipeline = read_pipeline(config)?;
-    let run_config = config.run_config(&pipeline);
+    let run_config = config.run_config(&pipeline)?;
     let mut run = pipeline.run(run_config);
+    run.cancel_on_signals()?;
 
     run.start()?;
-    let succ = run.is_completed() && run.is_succeeded();
+    println!("{run}");
+    let exit_code = match run.is_completed() && run.is_succeeded() {
+        _ if run.is_cancelled() => CANCELLED,
+        _ if run.is_timed_out() => TIMED_OUT,
+        true => 0,
+        false => 1,
+    };
     run.cleanup()?;
-    Ok(succ)
+    Ok(exit_code)
 }
@@ -1,35 +1,71 @@
-use crate::config::{Config, RunConfig};
+use crate::config::{Config, PipelineConfig, RunConfig};
 use crate::err::{Error, Result};
 use crate::run::Run;
+pub use condition::{Condition, Context};
 use raw_pipeline::RawPipeline;
+pub use resources::{Resources, cpus_arg};
+pub use selection::{Reason, Selection};
 use std::collections::HashMap;
 use std::fs::File;
 use std::io::Read;
 use std::num::NonZeroUsize;
-pub use task::Task;
+use std::time::Duration;
+#[cfg(test)]
+pub use task::{Retry, TaskOptions};
+pub use task::{Running, Task};
 pub use task_id::{TaskId, TaskIds};
 
+mod condition;
+mod duration;
+mod glob;
+mod interpolate;
+mod location;
+mod matrix;
 mod raw_pipeline;
 mod raw_task;
+mod ref_filter;
+mod resources;
+mod selection;
 mod task;
 mod task_id;
 mod task_name;
+mod template;
 
 #[derive(Debug, PartialEq)]
 pub struct Pipeline {
     n_parallel: NonZeroUsize,
+    timeout: Option<Duration>,
+    fail_fast: bool,
+    capacity: Resources,
     tasks: HashMap<TaskId, Task>,
+    /// For reading the pipelines generated by the tasks.
+    config: PipelineConfig,
+    selection: Option<Selection>,
 }
 
 impl Pipeline {
-    fn from_raw(raw_pipeline: RawPipeline, default_image: &Option<String>) -> Result<Self> {
+    /// See [RawPipeline::select] for how one of the named pipelines is selected.
+    fn from_raw(raw_pipeline: RawPipeline, config: &PipelineConfig) -> Result<Self> {
+        let (raw_pipeline, selection) = raw_pipeline.select(config)?;
         let n_parallel = raw_pipeline.n_parallel()?;
-        let tasks = raw_pipeline.tasks(default_image.as_deref())?;
-        if let Some(task_ids) = check_cycles(&tasks) {
-            let names = task_ids.ids().map(|i| tasks[&i].to_string()).collect();
-            return Err(Error::DependencyCycle(names));
-        }
-        Ok(Self { tasks, n_parallel })
+        let timeout = raw_pipeline.timeout()?;
+        let fail_fast = raw_pipeline.fail_fast();
+        let capacity = raw_pipeline.capacity()?;
+        let tasks = raw_pipeline.tasks(config)?;
+        Ok(Self {
+            tasks,
+            n_parallel,
+            timeout,
+            fail_fast,
+            capacity,
+            config: config.clone(),
+            selection,
+        })
+    }
+
+    /// The named pipeline that was selected, if the file defines `pipelines`.
+    pub fn selection(&self) -> Option<&Selection> {
+        self.selection.as_ref()
     }
 
     /// Max width for prepending task name to stdout
@@ -39,54 +75,156 @@ impl Pipeline {
         (min_tasks.unwrap_or(0) + 2).min(min_default)
     }
 
-    pub fn read_from(rdr: impl Read, default_image: &Option<String>) -> Result<Self> {
-        Self::from_raw(
-            serde_yaml::from_reader::<_, RawPipeline>(rdr)?,
-            default_image,
-        )
+    pub fn read_from(mut rdr: impl Read, config: &PipelineConfig) -> Result<Self> {
+        let mut src = String::new();
+        rdr.read_to_string(&mut src)?;
+        Self::from_raw(RawPipeline::parse(&src)?, config)
     }
 
     pub fn run(self, config: RunConfig) -> Run {
-        Run::new(self.n_parallel.get(), config, self.tasks)
+        let n = self.n_parallel.get();
+        let (timeout, fail_fast) = (self.timeout, self.fail_fast);
+        let (tasks, capacity) = (self.tasks, self.capacity);
+        Run::new(n, config, self.config, tasks, timeout, fail_fast, capacity)
+    }
+}
+
+/// Read a pipeline generated by the task `generator` at runtime.
+///
+/// The ids of the tasks start from `first_id` and all of the tasks depend on the generator.
+/// The tasks can depend only on each other, and the settings of the pipeline other
+/// than the tasks (eg. `n_parallel` and `timeout`) are ignored.
+pub fn read_generated(
+    mut rdr: impl Read,
+    config: &PipelineConfig,
+    first_id: TaskId,
+    generator: TaskId,
+) -> Result<HashMap<TaskId, Task>> {
+    let mut src = String::new();
+    rdr.read_to_string(&mut src)?;
+    let raw_pipeline = RawPipeline::parse(&src)?;
+    let mut tasks = raw_pipeline.tasks_from(config, first_id)?;
+    for task in tasks.values_mut() {
+        task.add_depends(TaskIds::from(generator));
+    }
+    Ok(tasks)
+}
+
+/// JSON Schema of the pipeline definition, generated from the types it is read into.
+pub fn schema() -> String {
+    let schema = schemars::schema_for!(RawPipeline);
+    serde_json::to_string_pretty(&schema).expect("Schema is not valid JSON")
+}
+
+/// Keys allowed in the mapping at `path` (eg. `tasks[0]`) of a pipeline, from its schema.
+/// `None` if the path is not a mapping with a fixed set of keys.
+fn allowed_keys(path: &str) -> Option<Vec<String>> {
+    let schema = schemars::schema_for!(RawPipeline);
+    let root = schema.as_value();
+    let mut node = root;
+    for segment in path.split(['.', '[']).filter(|s| !s.is_empty()) {
+        let alternatives = alternatives(root, node);
+        node = match segment.ends_with(']') {
+            true => alternatives.into_iter().find_map(|n| n.get("items"))?,
+            false => alternatives.into_iter().find_map(|n| {
+                let property = n.get("properties").and_then(|p| p.get(segment));
+                property.or(n.get("additionalProperties").filter(|a| a.is_object()))
+            })?,
+        };
     }
+    let alternatives = alternatives(root, node);
+    let properties = alternatives
+        .into_iter()
+        .find_map(|n| n.get("properties")?.as_object())?;
+    Some(properties.keys().cloned().collect())
+}
+
+/// The schema `node` and its `anyOf` etc alternatives, with the references resolved.
+fn alternatives<'a>(
+    root: &'a serde_json::Value,
+    node: &'a serde_json::Value,
+) -> Vec<&'a serde_json::Value> {
+    let node = match node.get("$ref").and_then(|r| r.as_str()) {
+        Some("#") => root,
+        Some(r) => match r.strip_prefix("#/$defs/") {
+            Some(name) => &root["$defs"][name],
+            None => return vec![],
+        },
+        None => node,
+    };
+    let mut nodes = vec![node];
+    for key in ["anyOf", "oneOf", "allOf"] {
+        for alternative in node
+            .get(key)
+            .and_then(|a| a.as_array())
+            .into_iter()
+            .flatten()
+        {
+            nodes.extend(alternatives(root, alternative));
+        }
+    }
+    nodes
 }
 
 /// Read the pipeline and validate it (no cycles, all dependencies exist etc).
 pub fn read_pipeline(config: &Config) -> Result<Pipeline> {
     let file = File::open(config.pipeline_filename())?;
-    let default_image = config.default_image();
-    Pipeline::read_from(file, default_image)
+    Pipeline::read_from(file, &config.pipeline_config())
 }
 
 /// Simply run DFS to check for cycles, if any task(id) leads back to itself
-/// in the dependency graph, then we have a cycle.
-fn check_cycles(tasks: &HashMap<TaskId, Task>) -> Option<TaskIds> {
+/// in the dependency graph, then we have a cycle. Dependencies outside of `tasks`
+/// are assumed to be checked already.
+///
+/// Cycles of explicit dependencies are reported by [RawPipeline] already, so this catches
+/// the ones caused by the implicit dependencies (eg. stages). The cycle is reported at
+/// the location of its first task given by `locate`.
+fn check_cycles(
+    tasks: &HashMap<TaskId, Task>,
+    locate: impl Fn(&str) -> Option<String>,
+) -> Result<()> {
     let mut visited = TaskIds::default();
-    tasks
-        .keys()
-        .copied()
-        .find_map(|i| visit(i, &mut visited, &mut TaskIds::default(), tasks))
+    // in the order of the ids, so that the same cycle is reported on every run
+    let mut ids: Vec<_> = tasks.keys().copied().collect();
+    ids.sort();
+    let cycle = ids
+        .into_iter()
+        .find_map(|i| visit(i, &mut visited, &mut vec![], tasks));
+    match cycle {
+        Some(path) => {
+            let names: Vec<_> = path.iter().map(|i| tasks[i].name().to_string()).collect();
+            let e = Error::DependencyCycle(names.clone());
+            match locate(&names[0]) {
+                Some(at) => Err(Error::Located(at, Box::new(e))),
+                None => Err(e),
+            }
+        }
+        None => Ok(()),
+    }
 }
 
+/// `path` contains the tasks being checked, the cycle is returned as the path
+/// from the task to itself.
 fn visit(
     task_id: TaskId,
     checked: &mut TaskIds,
-    checking: &mut TaskIds,
+    path: &mut Vec<TaskId>,
     tasks: &HashMap<TaskId, Task>,
-) -> Option<TaskIds> {
+) -> Option<Vec<TaskId>> {
     let task_ids = TaskIds::from(task_id);
     if !(*checked & task_ids).is_empty() {
         return None; // already checked so we can skip this
     }
-    if !(*checking & task_ids).is_empty() {
-        return Some(*checking); // cycle
+    if let Some(start) = path.iter().position(|i| *i == task_id) {
+        return Some([&path[start..], &[task_id]].concat()); // cycle
     }
-    *checking |= task_ids;
-    let deps = tasks[&task_id].depends();
-    if let Some(i) = deps.ids().find_map(|d| visit(d, checked, checking, tasks)) {
-        return Some(i);
+    let task = tasks.get(&task_id)?;
+    path.push(task_id);
+    let deps = task.depends();
+    if let Some(cycle) = deps.ids().find_map(|d| visit(d, checked, path, tasks)) {
+        return Some(cycle);
     }
-    *checking &= !task_ids;
+    path.pop();
     *checked |= task_ids;
     None
 }
@@ -94,13 +232,34 @@ fn visit(
 #[cfg(test)]
 mod test {
     use super::*;
+    use crate::config::GitRef;
+    use std::collections::BTreeMap;
+    use std::path::PathBuf;
     use std::thread::available_parallelism;
+    use task::{Retry, TaskOptions};
+
+    fn config(default_image: Option<&str>) -> PipelineConfig {
+        let git_ref = GitRef::Branch(String::from("main"));
+        config_with(default_image, git_ref)
+    }
+
+    fn config_with(default_image: Option<&str>, git_ref: GitRef) -> PipelineConfig {
+        let image = default_image.map(String::from);
+        PipelineConfig::new(
+            image,
+            git_ref,
+            PathBuf::from("."),
+            String::from("runr.yaml"),
+            BTreeMap::new(),
+            None,
+        )
+    }
 
     #[test]
     fn parse_empty_pipeline() {
         let yaml = "tasks:";
         let raw_tasks: RawPipeline = serde_yaml::from_str(yaml).unwrap();
-        let pipeline = Pipeline::from_raw(raw_tasks, &Some(String::new())).unwrap();
+        let pipeline = Pipeline::from_raw(raw_tasks, &config(Some(""))).unwrap();
         assert_eq!(pipeline.tasks, HashMap::new());
         assert_eq!(pipeline.n_parallel, NonZeroUsize::new(1).unwrap());
     }
@@ -121,13 +280,14 @@ mod test {
           depends: ["step-1"]
         "#;
         let raw_tasks: RawPipeline = serde_yaml::from_str(yaml).unwrap();
-        let pipeline = Pipeline::from_raw(raw_tasks, &None).unwrap();
+        let pipeline = Pipeline::from_raw(raw_tasks, &config(None)).unwrap();
         let task0 = Task::CommandLine {
             name: String::from("step-1"),
             commands: String::from("echo\nexit 0\n"),
             depends: TaskIds::default(),
+            options: TaskOptions::default(),
         };
-        let task1 = Task::PullImage(String::from("image0"));
+        let task1 = Task::PullImage(String::from("image0"), Retry::default());
         let task2 = Task::Container {
             name: String::from("n"),
             commands: String::from("echo n"),
@@ -136,6 +296,7 @@ mod test {
                 .into_iter()
                 .map(|i| TaskId::try_from(i).unwrap())
                 .collect(),
+            options: TaskOptions::default(),
         };
         let tasks = [task0, task1, task2]
             .into_iter()
@@ -164,9 +325,9 @@ mod test {
         "#;
         let default_img = String::from("img77");
         let raw_tasks: RawPipeline = serde_yaml::from_str(yaml).unwrap();
-        let pipeline = Pipeline::from_raw(raw_tasks, &Some(default_img.clone())).unwrap();
+        let pipeline = Pipeline::from_raw(raw_tasks, &config(Some(&default_img))).unwrap();
 
-        let task0 = Task::PullImage(default_img);
+        let task0 = Task::PullImage(default_img, Retry::default());
         let task1 = Task::Container {
             name: String::from("step-1"),
             commands: String::from("echo\nexit 0\n"),
@@ -175,8 +336,9 @@ mod test {
                 .into_iter()
                 .map(|i| TaskId::try_from(i).unwrap())
                 .collect(),
+            options: TaskOptions::default(),
         };
-        let task2 = Task::PullImage(String::from("img0"));
+        let task2 = Task::PullImage(String::from("img0"), Retry::default());
         let task3 = Task::Container {
             name: String::from("n"),
             commands: String::from("echo n"),
@@ -185,6 +347,7 @@ mod test {
                 .into_iter()
                 .map(|i| TaskId::try_from(i).unwrap())
                 .collect(),
+            options: TaskOptions::default(),
         };
 
         let tasks = vec![task0, task1, task2, task3]
@@ -205,7 +368,7 @@ mod test {
           depends: ["self"]
         "#;
         let raw_tasks: RawPipeline = serde_yaml::from_str(yaml).unwrap();
-        assert!(Pipeline::from_raw(raw_tasks, &None).is_err());
+        assert!(Pipeline::from_raw(raw_tasks, &config(None)).is_err());
     }
 
     #[test]
@@ -228,6 +391,76 @@ mod test {
           depends: ["step-1"]
         "#;
         let raw_tasks: RawPipeline = serde_yaml::from_str(yaml).unwrap();
-        assert!(Pipeline::from_raw(raw_tasks, &None).is_err());
+        assert!(Pipeline::from_raw(raw_tasks, &config(None)).is_err());
+    }
+
+    #[test]
+    fn stage_cycle() {
+        let yaml = r#"
+        stages: [build, test]
+        tasks:
+        - commands: cmd
+          name: build
+          stage: build
+          depends: [test]
+        - commands: cmd
+          name: test
+          stage: test
+        "#;
+        let raw_tasks = RawPipeline::parse(yaml).unwrap();
+        match Pipeline::from_raw(raw_tasks, &config(None)) {
+            Err(Error::Located(at, e)) if matches!(*e, Error::DependencyCycle(_)) => {
+                assert_eq!(at, "runr.yaml:4:11");
+            }
+            res => panic!("expected a located cycle, got {res:?}"),
+        }
+    }
+
+    #[test]
+    fn generated_tasks_depend_on_generator() {
+        let yaml = r#"
+        tasks:
+        - commands: cmd
+          name: test-a
+        - commands: cmd
+          name: test-b
+          depends: [test-a]
+        "#;
+        let id = |i| TaskId::try_from(i).unwrap();
+        let tasks = read_generated(yaml.as_bytes(), &config(None), id(3), id(1)).unwrap();
+        assert_eq!(tasks.len(), 2);
+        assert_eq!(tasks[&id(3)].name(), "test-a");
+        assert_eq!(tasks[&id(3)].depends(), TaskIds::from(id(1)));
+        assert_eq!(
+            tasks[&id(4)].depends(),
+            [id(1), id(3)].into_iter().collect()
+        );
+
+        let yaml = r#"
+        tasks:
+        - commands: cmd
+          name: test-a
+          depends: [test-b]
+        - commands: cmd
+          name: test-b
+          depends: [test-a]
+        "#;
+        assert!(matches!(
+            read_generated(yaml.as_bytes(), &config(None), id(3), id(1)),
+            Err(Error::Located(_, e)) if matches!(*e, Error::DependencyCycle(_))
+        ));
+    }
+
+    #[test]
+    fn schema_is_generated_from_the_types() {
+        let schema: serde_json::Value = serde_json::from_str(&schema()).unwrap();
+        assert_eq!(schema["additionalProperties"], false);
+        let task = &schema["$defs"]["RawTask"];
+        assert_eq!(task["additionalProperties"], false);
+        assert_eq!(task["required"], serde_json::json!(["name"]));
+        assert!(task["properties"]["depends"].is_object());
+        // renamed and skipped fields
+        assert!(task["properties"]["if"].is_object());
+        assert!(task["properties"]["source"].is_null());
     }
 }
@@ -0,0 +1,340 @@
+use crate::config::GitRef;
+use std::collections::BTreeMap;
+use std::iter::Peekable;
+use std::str::Chars;
+use std::{env, fmt};
+
+/// Condition for running a task, given with `if:`, eg.
+/// ```yaml
+/// if: branch == 'main' && env.DEPLOY
+/// ```
+///
+/// The expressions consist of
+/// * values: `branch`, `tag` and `commit` of the run, `env.<NAME>` (task environment,
+///   then the environment of runr) and quoted strings. Unset values are empty strings.
+/// * comparisons `==` and `!=`. A value by itself is true if it is non-empty.
+/// * dependency outcomes: `success()` (all dependencies succeeded),
+///   `failure()` (some dependency failed) and `always()`.
+/// * `!`, `&&`, `||` and parentheses.
+///
+/// If the expression does not use any of the dependency outcomes, it is implicitly
+/// combined with the default outcome, which is `success()` for regular tasks, ie.
+/// `if: env.X` means `if: success() && env.X`.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Condition {
+    Or(Box<Condition>, Box<Condition>),
+    And(Box<Condition>, Box<Condition>),
+    Not(Box<Condition>),
+    Eq(Value, Value),
+    Ne(Value, Value),
+    Value(Value),
+    Outcome(Outcome),
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum Value {
+    Branch,
+    Tag,
+    Commit,
+    Env(String),
+    Literal(String),
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Outcome {
+    Success,
+    Failure,
+    Always,
+}
+
+/// Everything a [Condition] can refer to.
+#[derive(Debug)]
+pub struct Context<'a> {
+    pub git_ref: &'a GitRef,
+    pub commit: &'a str,
+    pub env: &'a BTreeMap<String, String>,
+    /// All the dependencies succeeded (ie. none failed or were skipped).
+    pub deps_succeeded: bool,
+    /// At least one of the dependencies failed.
+    pub deps_failed: bool,
+}
+
+impl Condition {
+    /// Parse the expression, see [Condition] for the syntax.
+    /// `default` is used if the expression does not refer to any outcome.
+    pub fn parse(expr: &str, default: Outcome) -> Result<Self, String> {
+        let mut parser = Parser {
+            tokens: tokenize(expr)?.into_iter().peekable(),
+        };
+        let cond = parser.or()?;
+        if let Some(token) = parser.tokens.next() {
+            return Err(format!("unexpected {token}"));
+        }
+        match cond.has_outcome() {
+            true => Ok(cond),
+            false => Ok(Self::And(Box::new(Self::Outcome(default)), Box::new(cond))),
+        }
+    }
+
+    fn has_outcome(&self) -> bool {
+        match self {
+            Self::Or(a, b) | Self::And(a, b) => a.has_outcome() || b.has_outcome(),
+            Self::Not(a) => a.has_outcome(),
+            Self::Eq(..) | Self::Ne(..) | Self::Value(_) => false,
+            Self::Outcome(_) => true,
+        }
+    }
+
+    /// Check if the condition can hold when some dependency failed, ie. it uses
+    /// `failure()` or `always()` (or negates an outcome).
+    pub fn allows_failed_deps(&self) -> bool {
+        match self {
+            Self::Or(a, b) | Self::And(a, b) => a.allows_failed_deps() || b.allows_failed_deps(),
+            Self::Not(a) => a.has_outcome(),
+            Self::Eq(..) | Self::Ne(..) | Self::Value(_) => false,
+            Self::Outcome(outcome) => *outcome != Outcome::Success,
+        }
+    }
+
+    pub fn eval(&self, ctx: &Context) -> bool {
+        match self {
+            Self::Or(a, b) => a.eval(ctx) || b.eval(ctx),
+            Self::And(a, b) => a.eval(ctx) && b.eval(ctx),
+            Self::Not(a) => !a.eval(ctx),
+            Self::Eq(a, b) => a.eval(ctx) == b.eval(ctx),
+            Self::Ne(a, b) => a.eval(ctx) != b.eval(ctx),
+            Self::Value(a) => !a.eval(ctx).is_empty(),
+            Self::Outcome(Outcome::Success) => ctx.deps_succeeded,
+            Self::Outcome(Outcome::Failure) => ctx.deps_failed,
+            Self::Outcome(Outcome::Always) => true,
+        }
+    }
+}
+
+impl Value {
+    fn eval(&self, ctx: &Context) -> String {
+        match (self, ctx.git_ref) {
+            (Self::Branch, GitRef::Branch(b)) => b.clone(),
+            (Self::Tag, GitRef::Tag(t)) => t.clone(),
+            (Self::Branch | Self::Tag, _) => String::new(),
+            (Self::Commit, _) => ctx.commit.to_string(),
+            (Self::Env(name), _) => match ctx.env.get(name) {
+                Some(v) => v.clone(),
+                None => env::var(name).unwrap_or_default(),
+            },
+            (Self::Literal(s), _) => s.clone(),
+        }
+    }
+}
+
+#[derive(Debug, PartialEq)]
+enum Token {
+    LParen,
+    RParen,
+    Not,
+    And,
+    Or,
+    Eq,
+    Ne,
+    Str(String),
+    Ident(String),
+}
+
+impl fmt::Display for Token {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Token::LParen => write!(f, "'('"),
+            Token::RParen => write!(f, "')'"),
+            Token::Not => write!(f, "'!'"),
+            Token::And => write!(f, "'&&'"),
+            Token::Or => write!(f, "'||'"),
+            Token::Eq => write!(f, "'=='"),
+            Token::Ne => write!(f, "'!='"),
+            Token::Str(s) => write!(f, "'{s}'"),
+            Token::Ident(s) => write!(f, "{s}"),
+        }
+    }
+}
+
+fn tokenize(expr: &str) -> Result<Vec<Token>, String> {
+    let mut chars = expr.chars().peekable();
+    let mut tokens = vec![];
+    while let Some(c) = chars.next() {
+        let token = match c {
+            c if c.is_whitespace() => continue,
+            '(' => Token::LParen,
+            ')' => Token::RParen,
+            '!' if chars.next_if_eq(&'=').is_some() => Token::Ne,
+            '!' => Token::Not,
+            '=' if chars.next_if_eq(&'=').is_some() => Token::Eq,
+            '&' if chars.next_if_eq(&'&').is_some() => Token::And,
+            '|' if chars.next_if_eq(&'|').is_some() => Token::Or,
+            '\'' | '"' => Token::Str(string(c, &mut chars)?),
+            c if c.is_ascii_alphabetic() || c == '_' => {
+                let mut ident = String::from(c);
+                while let Some(c) =
+                    chars.next_if(|c| c.is_ascii_alphanumeric() || "_.".contains(*c))
+                {
+                    ident.push(c);
+                }
+                Token::Ident(ident)
+            }
+            c => return Err(format!("unexpected character '{c}'")),
+        };
+        tokens.push(token);
+    }
+    Ok(tokens)
+}
+
+fn string(quote: char, chars: &mut Peekable<Chars>) -> Result<String, String> {
+    let mut s = String::new();
+    for c in chars.by_ref() {
+        if c == quote {
+            return Ok(s);
+        }
+        s.push(c);
+    }
+    Err(String::from("unterminated string"))
+}
+
+struct Parser<I: Iterator<Item = Token>> {
+    tokens: Peekable<I>,
+}
+
+impl<I: Iterator<Item = Token>> Parser<I> {
+    fn or(&mut self) -> Result<Condition, String> {
+        let mut cond = self.and()?;
+        while self.tokens.next_if_eq(&Token::Or).is_some() {
+            cond = Condition::Or(Box::new(cond), Box::new(self.and()?));
+        }
+        Ok(cond)
+    }
+
+    fn and(&mut self) -> Result<Condition, String> {
+        let mut cond = self.unary()?;
+        while self.tokens.next_if_eq(&Token::And).is_some() {
+            cond = Condition::And(Box::new(cond), Box::new(self.unary()?));
+        }
+        Ok(cond)
+    }
+
+    fn unary(&mut self) -> Result<Condition, String> {
+        if self.tokens.next_if_eq(&Token::Not).is_some() {
+            return Ok(Condition::Not(Box::new(self.unary()?)));
+        }
+        if self.tokens.next_if_eq(&Token::LParen).is_some() {
+            let cond = self.or()?;
+            self.expect(Token::RParen)?;
+            return Ok(cond);
+        }
+        if let Some(outcome) = self.outcome()? {
+            return Ok(Condition::Outcome(outcome));
+        }
+        let value = self.value()?;
+        if self.tokens.next_if_eq(&Token::Eq).is_some() {
+            return Ok(Condition::Eq(value, self.value()?));
+        }
+        if self.tokens.next_if_eq(&Token::Ne).is_some() {
+            return Ok(Condition::Ne(value, self.value()?));
+        }
+        Ok(Condition::Value(value))
+    }
+
+    fn outcome(&mut self) -> Result<Option<Outcome>, String> {
+        let outcome = match self.tokens.peek() {
+            Some(Token::Ident(i)) if i == "success" => Outcome::Success,
+            Some(Token::Ident(i)) if i == "failure" => Outcome::Failure,
+            Some(Token::Ident(i)) if i == "always" => Outcome::Always,
+            _ => return Ok(None),
+        };
+        self.tokens.next();
+        self.expect(Token::LParen)?;
+        self.expect(Token::RParen)?;
+        Ok(Some(outcome))
+    }
+
+    fn value(&mut self) -> Result<Value, String> {
+        match self.tokens.next() {
+            Some(Token::Str(s)) => Ok(Value::Literal(s)),
+            Some(Token::Ident(i)) => match i.as_str() {
+                "branch" => Ok(Value::Branch),
+                "tag" => Ok(Value::Tag),
+                "commit" => Ok(Value::Commit),
+                i => match i.strip_prefix("env.") {
+                    Some(name) if !name.is_empty() => Ok(Value::Env(name.to_string())),
+                    _ => Err(format!("unknown value {i}")),
+                },
+            },
+            Some(token) => Err(format!("unexpected {token}")),
+            None => Err(String::from("unexpected end of expression")),
+        }
+    }
+
+    fn expect(&mut self, token: Token) -> Result<(), String> {
+        match self.tokens.next() {
+            Some(t) if t == token => Ok(()),
+            Some(t) => Err(format!("expected {token}, got {t}")),
+            None => Err(format!("expected {token}")),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn eval(expr: &str, git_ref: GitRef, deps_succeeded: bool) -> bool {
+        let env = [(String::from("SET"), String::from("1"))].into();
+        let ctx = Context {
+            git_ref: &git_ref,
+            commit: "abc123",
+            env: &env,
+            deps_succeeded,
+            deps_failed: !deps_succeeded,
+        };
+        Condition::parse(expr, Outcome::Success).unwrap().eval(&ctx)
+    }
+
+    #[test]
+    fn values_and_operators() {
+        let main = || GitRef::Branch(String::from("main"));
+        assert!(eval("branch == 'main'", main(), true));
+        assert!(eval(
+            "branch != \"dev\" && commit == 'abc123'",
+            main(),
+            true
+        ));
+        assert!(!eval("tag", main(), true));
+        assert!(eval("tag == 'v1'", GitRef::Tag(String::from("v1")), true));
+        assert!(eval("env.SET && !env.RUNR_SURELY_UNSET", main(), true));
+        assert!(eval("(tag || branch == 'dev') || env.SET", main(), true));
+        assert!(!eval("!(branch == 'main')", main(), true));
+    }
+
+    #[test]
+    fn success_is_implicit_unless_outcome_is_used() {
+        let main = || GitRef::Branch(String::from("main"));
+        assert!(!eval("branch == 'main'", main(), false));
+        assert!(eval("failure()", main(), false));
+        assert!(!eval("failure()", main(), true));
+        assert!(eval("always() && branch == 'main'", main(), false));
+        assert!(eval("success() || failure()", main(), false));
+    }
+
+    #[test]
+    fn invalid_expressions_fail() {
+        for expr in [
+            "",
+            "branch ==",
+            "(branch",
+            "branch == 'main",
+            "unknown",
+            "env.",
+            "success",
+            "branch = 'main'",
+            "branch 'main'",
+        ] {
+            assert!(Condition::parse(expr, Outcome::Success).is_err(), "{expr}");
+        }
+    }
+}
@@ -0,0 +1,60 @@
+use std::time::Duration;
+
+/// Parse durations such as `90s`, `10m` or `1h30m`.
+/// Supported units are `h`, `m`, `s` and `ms`.
+pub fn parse(s: &str) -> Result<Duration, String> {
+    let invalid = || format!("invalid duration '{s}', expected eg. 10m or 1h30m");
+    let mut total = Duration::ZERO;
+    let mut rest = s.trim();
+    if rest.is_empty() {
+        return Err(invalid());
+    }
+    while !rest.is_empty() {
+        let n_digits = rest
+            .find(|c: char| !c.is_ascii_digit())
+            .ok_or_else(invalid)?;
+        let value: u64 = rest[..n_digits].parse().map_err(|_| invalid())?;
+        rest = &rest[n_digits..];
+        let n_unit = rest
+            .find(|c: char| c.is_ascii_digit())
+            .unwrap_or(rest.len());
+        let duration = match &rest[..n_unit] {
+            "h" => value.checked_mul(3600).map(Duration::from_secs),
+            "m" => value.checked_mul(60).map(Duration::from_secs),
+            "s" => Some(Duration::from_secs(value)),
+            "ms" => Some(Duration::from_millis(value)),
+            _ => return Err(invalid()),
+        };
+        total = duration
+            .and_then(|d| total.checked_add(d))
+            .ok_or_else(invalid)?;
+        rest = &rest[n_unit..];
+    }
+    Ok(total)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn durations_are_parsed() {
+        assert_eq!(parse("10m"), Ok(Duration::from_secs(600)));
+        assert_eq!(parse("1h30m"), Ok(Duration::from_secs(5400)));
+        assert_eq!(parse("90s"), Ok(Duration::from_secs(90)));
+        assert_eq!(parse("1s500ms"), Ok(Duration::from_millis(1500)));
+        let too_long = format!("{}s1s", u64::MAX);
+        for s in [
+            "",
+            "10",
+            "m",
+            "10x",
+            "1.5h",
+            "-1s",
+            "99999999999999999h",
+            &too_long,
+        ] {
+            assert!(parse(s).is_err(), "{s}");
+        }
+    }
+}
@@ -0,0 +1,54 @@
+/// Match `text` against a glob `pattern`, where
+/// * `?` matches any single character except `/`
+/// * `*` matches any sequence of characters except `/`
+/// * `**` matches any sequence of characters, and `**/` also matches nothing
+///
+/// Every other character matches only itself.
+pub fn matches(pattern: &str, text: &str) -> bool {
+    let pattern: Vec<_> = pattern.chars().collect();
+    let text: Vec<_> = text.chars().collect();
+    matches_chars(&pattern, &text)
+}
+
+fn matches_chars(pattern: &[char], text: &[char]) -> bool {
+    match pattern {
+        [] => text.is_empty(),
+        ['*', '*', '/', rest @ ..] => {
+            matches_chars(rest, text)
+                || (0..text.len()).any(|i| text[i] == '/' && matches_chars(rest, &text[i + 1..]))
+        }
+        ['*', '*', rest @ ..] => (0..=text.len()).any(|i| matches_chars(rest, &text[i..])),
+        ['*', rest @ ..] => {
+            let end = text.iter().position(|c| *c == '/').unwrap_or(text.len());
+            (0..=end).any(|i| matches_chars(rest, &text[i..]))
+        }
+        ['?', rest @ ..] => matches!(text, [c, ..] if *c != '/') && matches_chars(rest, &text[1..]),
+        [c, rest @ ..] => text.first() == Some(c) && matches_chars(rest, &text[1..]),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn literals_and_wildcards() {
+        assert!(matches("main", "main"));
+        assert!(!matches("main", "mainline"));
+        assert!(matches("v?.?", "v1.2"));
+        assert!(!matches("v?", "v/"));
+        assert!(matches("release/*", "release/1.0"));
+        assert!(!matches("release/*", "release/1.0/fix"));
+        assert!(!matches("*", "feature/x"));
+    }
+
+    #[test]
+    fn double_star_crosses_directories() {
+        assert!(matches("backend/**", "backend/src/main.rs"));
+        assert!(matches("**/Cargo.toml", "Cargo.toml"));
+        assert!(matches("**/Cargo.toml", "crates/a/Cargo.toml"));
+        assert!(matches("src/**/*.rs", "src/lib.rs"));
+        assert!(matches("src/**/*.rs", "src/a/b/lib.rs"));
+        assert!(!matches("src/**/*.rs", "tests/lib.rs"));
+    }
+}
@@ -0,0 +1,86 @@
+/// Replace `${name}` in `s` with the value given by `lookup`, `$${` is replaced with `${`.
+/// Fails on undefined variables.
+pub fn interpolate(s: &str, lookup: impl Fn(&str) -> Option<String>) -> Result<String, String> {
+    interpolate_known(s, lookup, |_| true)
+}
+
+/// Replace `${name}` in `s` as with [interpolate], but keep undefined variables (and
+/// unterminated `${`) as they are, eg. for shell parameter expansions in commands.
+/// Fails only on the undefined variables for which `required` holds.
+pub fn interpolate_known(
+    s: &str,
+    lookup: impl Fn(&str) -> Option<String>,
+    required: impl Fn(&str) -> bool,
+) -> Result<String, String> {
+    let mut result = String::new();
+    let mut rest = s;
+    while let Some(i) = rest.find("${") {
+        if rest[..i].ends_with('$') {
+            // the preceding `$` is kept as the literal `$`
+            result.push_str(&rest[..i]);
+            result.push('{');
+            rest = &rest[i + 2..];
+            continue;
+        }
+        result.push_str(&rest[..i]);
+        let Some(len) = rest[i..].find('}') else {
+            if required("") {
+                return Err(String::from("unterminated '${'"));
+            }
+            rest = &rest[i..];
+            break;
+        };
+        let name = &rest[i + 2..i + len];
+        match lookup(name) {
+            Some(value) => result.push_str(&value),
+            None if required(name) => return Err(format!("undefined variable '{name}'")),
+            None => result.push_str(&rest[i..i + len + 1]),
+        }
+        rest = &rest[i + len + 1..];
+    }
+    result.push_str(rest);
+    Ok(result)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn lookup(name: &str) -> Option<String> {
+        match name {
+            "A" => Some(String::from("a")),
+            "runr.branch" => Some(String::from("main")),
+            _ => None,
+        }
+    }
+
+    #[test]
+    fn variables_are_replaced() {
+        let s = "${A}/${runr.branch}:${A}";
+        assert_eq!(interpolate(s, lookup), Ok(String::from("a/main:a")));
+        assert_eq!(interpolate("no vars", lookup), Ok(String::from("no vars")));
+        let s = "echo $${HOME} $A $$ ${A}";
+        assert_eq!(
+            interpolate(s, lookup),
+            Ok(String::from("echo ${HOME} $A $$ a"))
+        );
+    }
+
+    #[test]
+    fn unknown_variables_are_kept() {
+        let runr = |name: &str| name.starts_with("runr.");
+        let s = "echo ${HOME} ${VAR:-default} ${A} $${B} ${runr.branch} ${";
+        assert_eq!(
+            interpolate_known(s, lookup, runr),
+            Ok(String::from("echo ${HOME} ${VAR:-default} a ${B} main ${"))
+        );
+        assert!(interpolate_known("${runr.commit}", lookup, runr).is_err());
+    }
+
+    #[test]
+    fn undefined_and_unterminated_fail() {
+        assert!(interpolate("${B}", lookup).is_err());
+        assert!(interpolate("${}", lookup).is_err());
+        assert!(interpolate("${A", lookup).is_err());
+    }
+}
@@ -0,0 +1,199 @@
+use std::fmt;
+use yaml_rust2::parser::{Event, MarkedEventReceiver, Parser};
+use yaml_rust2::scanner::Marker;
+
+/// Position of a node in a YAML document.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct Location {
+    pub line: usize,
+    pub column: usize,
+}
+
+impl fmt::Display for Location {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}:{}", self.line, self.column)
+    }
+}
+
+/// Positions of the nodes of a YAML document by their path, eg. `tasks[0].depends[1]`
+/// (same as in the errors of `serde_yaml`). Scalars also have their value, so that
+/// eg. a dependency can be found by its name.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Locations(Vec<(String, Location, Option<String>)>);
+
+impl Locations {
+    /// Locations of the nodes in `src`, empty if `src` is not valid YAML.
+    pub fn parse(src: &str) -> Self {
+        let mut receiver = Receiver::default();
+        match Parser::new_from_str(src).load(&mut receiver, false) {
+            Ok(()) => receiver.locations,
+            Err(_) => Self::default(),
+        }
+    }
+
+    /// Locations of the nodes under `path`, with the paths relative to it.
+    pub fn scoped(&self, path: &str) -> Self {
+        let nodes = self.0.iter().filter_map(|(p, loc, value)| {
+            let rest = p.strip_prefix(path)?;
+            let rest = match rest.strip_prefix('.') {
+                Some(key) => key,
+                None if rest.is_empty() || rest.starts_with('[') => rest,
+                None => return None,
+            };
+            Some((rest.to_string(), *loc, value.clone()))
+        });
+        Self(nodes.collect())
+    }
+
+    pub fn get(&self, path: &str) -> Option<Location> {
+        self.0
+            .iter()
+            .find(|(p, _, _)| p == path)
+            .map(|(_, loc, _)| *loc)
+    }
+
+    /// Location of the item of the sequence at `path` with the given `value`.
+    pub fn find(&self, path: &str, value: &str) -> Option<Location> {
+        self.0
+            .iter()
+            .find(|(p, _, v)| {
+                p.strip_prefix(path).is_some_and(|i| i.starts_with('['))
+                    && v.as_deref() == Some(value)
+            })
+            .map(|(_, loc, _)| *loc)
+    }
+}
+
+/// Path of the mapping and the key at `location`, eg. `("tasks[0]", "depends")`.
+pub fn key_at(src: &str, location: Location) -> Option<(String, String)> {
+    let mut receiver = Receiver::default();
+    Parser::new_from_str(src).load(&mut receiver, false).ok()?;
+    receiver
+        .keys
+        .into_iter()
+        .find(|(_, _, loc)| *loc == location)
+        .map(|(path, key, _)| (path, key))
+}
+
+#[derive(Debug)]
+enum Node {
+    Sequence(usize),
+    /// The key of the current value, `None` if a key is expected next.
+    Mapping(Option<String>),
+}
+
+#[derive(Debug, Default)]
+struct Receiver {
+    /// Path of each of the nested nodes.
+    stack: Vec<(String, Node)>,
+    locations: Locations,
+    /// Collections located at their first scalar, as the parser marks block collections
+    /// after their first indicator or key.
+    unlocated: Vec<usize>,
+    /// Keys of the mappings by the path of the mapping.
+    keys: Vec<(String, String, Location)>,
+}
+
+impl Receiver {
+    /// Path of the next value, or `None` if the next node is a key.
+    fn next_path(&mut self) -> Option<String> {
+        let Some((path, node)) = self.stack.last_mut() else {
+            return Some(String::new());
+        };
+        match node {
+            Node::Sequence(i) => {
+                *i += 1;
+                Some(format!("{path}[{}]", *i - 1))
+            }
+            Node::Mapping(key) => {
+                let key = key.take()?;
+                match path.is_empty() {
+                    true => Some(key),
+                    false => Some(format!("{path}.{key}")),
+                }
+            }
+        }
+    }
+}
+
+impl MarkedEventReceiver for Receiver {
+    fn on_event(&mut self, ev: Event, mark: Marker) {
+        let start = matches!(
+            ev,
+            Event::Scalar(..)
+                | Event::SequenceStart(..)
+                | Event::MappingStart(..)
+                | Event::Alias(_)
+        );
+        if !start {
+            if matches!(ev, Event::SequenceEnd | Event::MappingEnd) {
+                self.stack.pop();
+            }
+            return;
+        }
+        let location = Location {
+            line: mark.line(),
+            column: mark.col() + 1,
+        };
+        if let Event::Scalar(..) = ev {
+            for i in self.unlocated.drain(..) {
+                self.locations.0[i].1 = location;
+            }
+        }
+        let Some(path) = self.next_path() else {
+            // keys are scalars, complex keys are not supported
+            if let (Some((path, Node::Mapping(key))), Event::Scalar(value, ..)) =
+                (self.stack.last_mut(), &ev)
+            {
+                *key = Some(value.clone());
+                self.keys.push((path.clone(), value.clone(), location));
+            }
+            return;
+        };
+        let value = match &ev {
+            Event::Scalar(value, ..) => Some(value.clone()),
+            _ => None,
+        };
+        self.locations.0.push((path.clone(), location, value));
+        let node = match ev {
+            Event::SequenceStart(..) => Node::Sequence(0),
+            Event::MappingStart(..) => Node::Mapping(None),
+            _ => return,
+        };
+        self.unlocated.push(self.locations.0.len() - 1);
+        self.stack.push((path, node));
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn nodes_are_located_by_path() {
+        let yaml = "tasks:\n- name: build\n  depends: [lint, test]\n- name: lint\n";
+        let locations = Locations::parse(yaml);
+        let at = |line, column| Some(Location { line, column });
+        assert_eq!(locations.get("tasks"), at(2, 3));
+        assert_eq!(locations.get("tasks[0].name"), at(2, 9));
+        assert_eq!(locations.get("tasks[1]"), at(4, 3));
+
+        let task = locations.scoped("tasks[0]");
+        assert_eq!(task.get(""), at(2, 3));
+        assert_eq!(task.get("depends[1]"), at(3, 19));
+        assert_eq!(task.find("depends", "lint"), at(3, 13));
+        assert_eq!(task.find("depends", "build"), None);
+        assert_eq!(locations.scoped("tasks[1]").get("depends"), None);
+
+        let key = |path: &str, key: &str| Some((path.to_string(), key.to_string()));
+        assert_eq!(
+            key_at(yaml, Location { line: 1, column: 1 }),
+            key("", "tasks")
+        );
+        assert_eq!(
+            key_at(yaml, Location { line: 3, column: 3 }),
+            key("tasks[0]", "depends")
+        );
+        assert_eq!(key_at(yaml, Location { line: 2, column: 9 }), None);
+    }
+}
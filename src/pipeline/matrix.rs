@@ -0,0 +1,180 @@
+use super::raw_task::RawTask;
+use crate::err::{Error, Result};
+use serde::de::{self, Deserializer, MapAccess, Visitor};
+use std::borrow::Cow;
+use std::fmt;
+
+/// Values to expand a single [RawTask] with, eg.
+/// ```yaml
+/// matrix:
+///   rust: ["1.91", nightly]
+///   features: [default, full]
+/// ```
+/// results in four tasks, one per combination. The order of the keys is preserved.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Matrix(Vec<(String, Vec<String>)>);
+
+impl Matrix {
+    /// All the combinations of the values, with the last key varying fastest.
+    fn combinations(&self) -> Vec<Vec<(&str, &str)>> {
+        self.0.iter().fold(vec![vec![]], |combs, (key, values)| {
+            combs
+                .iter()
+                .flat_map(|comb| {
+                    values.iter().map(|v| {
+                        let mut comb = comb.clone();
+                        comb.push((key.as_str(), v.as_str()));
+                        comb
+                    })
+                })
+                .collect()
+        })
+    }
+
+    /// Expand `task` into one task per combination.
+    ///
+    /// Each task gets a name of the form `name (key1=value1, key2=value2)`
+    /// and `${matrix.<key>}` is replaced with the corresponding value in
+    /// `commands` and `image`.
+    pub fn expand(&self, task: &RawTask) -> Result<Vec<RawTask>> {
+        if let Some((key, _)) = self.0.iter().find(|(_, values)| values.is_empty()) {
+            return Err(Error::InvalidMatrix(
+                task.name.clone(),
+                format!("'{key}' has no values"),
+            ));
+        }
+        let mut tasks = vec![];
+        for comb in self.combinations() {
+            let values: Vec<_> = comb.iter().map(|(k, v)| format!("{k}={v}")).collect();
+            let mut expanded = task.clone();
+            expanded.name = format!("{} ({})", task.name, values.join(", "));
+            expanded.matrix = None;
+            expanded.commands = substitute(&task.name, &task.commands, &comb)?;
+            if let Some(image) = &task.image {
+                expanded.image = Some(substitute(&task.name, image, &comb)?);
+            }
+            tasks.push(expanded);
+        }
+        Ok(tasks)
+    }
+}
+
+/// Replace `${matrix.<key>}` with the values, fails on unknown keys.
+fn substitute(task_name: &str, s: &str, comb: &[(&str, &str)]) -> Result<String> {
+    let s = comb.iter().fold(s.to_string(), |s, (k, v)| {
+        s.replace(&format!("${{matrix.{k}}}"), v)
+    });
+    match s.find("${matrix.") {
+        Some(i) => {
+            let var = s[i..].split_inclusive('}').next().unwrap_or_default();
+            Err(Error::InvalidMatrix(
+                task_name.to_string(),
+                format!("unknown variable {var}"),
+            ))
+        }
+        None => Ok(s),
+    }
+}
+
+impl<'de> serde::Deserialize<'de> for Matrix {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> std::result::Result<Self, D::Error> {
+        deserializer.deserialize_map(MatrixVisitor)
+    }
+}
+
+impl schemars::JsonSchema for Matrix {
+    fn schema_name() -> Cow<'static, str> {
+        Cow::Borrowed("Matrix")
+    }
+
+    /// Map from names to lists of scalar values, see [MatrixVisitor].
+    fn json_schema(_: &mut schemars::SchemaGenerator) -> schemars::Schema {
+        schemars::json_schema!({
+            "type": "object",
+            "additionalProperties": {
+                "type": "array",
+                "items": { "type": ["string", "number", "boolean"] }
+            }
+        })
+    }
+}
+
+struct MatrixVisitor;
+
+impl<'de> Visitor<'de> for MatrixVisitor {
+    type Value = Matrix;
+
+    fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "a map from names to lists of values")
+    }
+
+    fn visit_map<A: MapAccess<'de>>(self, mut map: A) -> std::result::Result<Matrix, A::Error> {
+        let mut entries = vec![];
+        while let Some((key, values)) = map.next_entry::<String, Vec<serde_yaml::Value>>()? {
+            let values = values
+                .into_iter()
+                .map(|v| match v {
+                    serde_yaml::Value::String(s) => Ok(s),
+                    serde_yaml::Value::Number(n) => Ok(n.to_string()),
+                    serde_yaml::Value::Bool(b) => Ok(b.to_string()),
+                    _ => Err(de::Error::custom(format!("non-scalar value for '{key}'"))),
+                })
+                .collect::<std::result::Result<_, _>>()?;
+            entries.push((key, values));
+        }
+        Ok(Matrix(entries))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn expands_all_combinations_in_order() {
+        let yaml = r#"
+            name: test
+            image: "rust:${matrix.rust}"
+            commands: cargo test --features ${matrix.features}
+            matrix:
+              rust: [1.91, nightly]
+              features: [default, full]
+        "#;
+        let task: RawTask = serde_yaml::from_str(yaml).unwrap();
+        let tasks = task.matrix.as_ref().unwrap().expand(&task).unwrap();
+        let names: Vec<_> = tasks.iter().map(|t| t.name.as_str()).collect();
+        assert_eq!(
+            names,
+            [
+                "test (rust=1.91, features=default)",
+                "test (rust=1.91, features=full)",
+                "test (rust=nightly, features=default)",
+                "test (rust=nightly, features=full)",
+            ]
+        );
+        assert_eq!(tasks[1].image.as_deref(), Some("rust:1.91"));
+        assert_eq!(tasks[1].commands, "cargo test --features full");
+        assert!(tasks.iter().all(|t| t.matrix.is_none()));
+    }
+
+    #[test]
+    fn unknown_variable_and_empty_values_fail() {
+        let yaml = r#"
+            name: test
+            commands: echo ${matrix.os}
+            matrix:
+              rust: [stable]
+        "#;
+        let task: RawTask = serde_yaml::from_str(yaml).unwrap();
+        assert!(task.matrix.as_ref().unwrap().expand(&task).is_err());
+
+        let yaml = r#"
+            name: test
+            commands: echo
+            matrix:
+              rust: []
+        "#;
+        let task: RawTask = serde_yaml::from_str(yaml).unwrap();
+        assert!(task.matrix.as_ref().unwrap().expand(&task).is_err());
+    }
+}
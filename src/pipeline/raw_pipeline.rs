@@ -1,22 +1,76 @@
+use super::duration;
+use super::glob;
+use super::interpolate::{interpolate, interpolate_known};
+use super::location::{self, Location, Locations};
 use super::raw_task::RawTask;
-use super::{Task, TaskId, TaskIds};
-use crate::err::Result;
+use super::ref_filter::RefFilter;
+use super::resources::{self, RawResources, Resources};
+use super::selection::{Reason, Rule, Selection};
+use super::task::{Retry, TaskOptions};
+use super::template::RawTemplate;
+use super::{Condition, Task, TaskId, TaskIds, allowed_keys, check_cycles};
+use crate::config::PipelineConfig;
+use crate::err::{Error, Result};
 use crate::pipeline::task_name::TaskNames;
-use std::collections::{HashMap, hash_map};
+use crate::suggest::closest;
+use std::collections::{BTreeMap, HashMap, HashSet, btree_map, hash_map};
+use std::fs;
 use std::num::NonZeroUsize;
+use std::path::{Component, Path, PathBuf};
 use std::thread;
+use std::time::Duration;
 
 /// Pipeline that is simply read from the input as is.
 /// This is further checked for undefined dependencies, cycles etc. and
 /// transformed into [super::Pipeline] that is run.
-#[derive(Debug, serde::Deserialize, PartialEq)]
+#[derive(Debug, Default, schemars::JsonSchema, serde::Deserialize, PartialEq)]
+#[serde(deny_unknown_fields)]
+#[schemars(
+    title = "runr pipeline",
+    description = "Pipeline definition, eg. runr.yaml"
+)]
 pub struct RawPipeline {
     default_image: Option<String>,
     n_parallel: Option<usize>,
+    shell: Option<String>,
+    timeout: Option<String>,
+    /// Stop the run on the first failure, defaults to true.
+    fail_fast: Option<bool>,
+    /// Resources of the machine shared by the running tasks, unlimited if unset.
+    capacity: Option<RawResources>,
+    pull_retries: Option<u32>,
+    pull_retry_delay: Option<String>,
+    variables: Option<BTreeMap<String, String>>,
+    env: Option<BTreeMap<String, String>>,
+    on: Option<RefFilter>,
+    stages: Option<Vec<String>>,
+    include: Option<Vec<String>>,
+    templates: Option<BTreeMap<String, RawTemplate>>,
+    #[serde(default)]
     tasks: Vec<RawTask>,
+    /// Named pipelines, one of which is selected by `rules` (or explicitly) and run.
+    pipelines: Option<BTreeMap<String, RawPipeline>>,
+    rules: Option<Vec<Rule>>,
 }
 
 impl RawPipeline {
+    /// Parse the pipeline from `src`, keeping the locations of the tasks for errors.
+    pub fn parse(src: &str) -> Result<Self> {
+        let raw_pipeline = serde_yaml::from_str(src).map_err(|e| unknown_key(src, e));
+        let mut raw_pipeline: Self = raw_pipeline?;
+        raw_pipeline.set_locations(&Locations::parse(src));
+        Ok(raw_pipeline)
+    }
+
+    fn set_locations(&mut self, locations: &Locations) {
+        for (i, task) in self.tasks.iter_mut().enumerate() {
+            task.locations = locations.scoped(&format!("tasks[{i}]"));
+        }
+        for (name, pipeline) in self.pipelines.iter_mut().flatten() {
+            pipeline.set_locations(&locations.scoped(&format!("pipelines.{name}")));
+        }
+    }
+
     /// Defaults to [thread::available_parallelism()] if set to zero.
     pub fn n_parallel(&self) -> Result<NonZeroUsize> {
         match self.n_parallel {
@@ -26,17 +80,141 @@ impl RawPipeline {
         }
     }
 
+    /// Select one of the named `pipelines`, either explicitly (see
+    /// [PipelineConfig::pipeline]) or with the first of the `rules` that matches the ref.
+    ///
+    /// If there are no named pipelines, the pipeline itself is returned. If none of the
+    /// rules match, the returned pipeline is empty.
+    pub fn select(mut self, config: &PipelineConfig) -> Result<(Self, Option<Selection>)> {
+        let Some(mut pipelines) = self.pipelines.take() else {
+            if self.rules.is_some() {
+                return Err(Error::InvalidPipelines(String::from(
+                    "rules without pipelines",
+                )));
+            }
+            if let Some(name) = config.pipeline() {
+                return Err(Error::UndefinedPipeline(name.to_string()));
+            }
+            return Ok((self, None));
+        };
+        let rules = self.rules.take().unwrap_or_default();
+        if self != Self::default() {
+            let e = "other settings cannot be defined alongside pipelines";
+            return Err(Error::InvalidPipelines(String::from(e)));
+        }
+        if let Some((name, _)) = pipelines.iter().find(|(_, p)| p.pipelines.is_some()) {
+            let e = format!("pipeline '{name}' cannot define pipelines");
+            return Err(Error::InvalidPipelines(e));
+        }
+        if let Some(rule) = rules.iter().find(|r| !pipelines.contains_key(&r.pipeline)) {
+            return Err(Error::UndefinedPipeline(rule.pipeline.clone()));
+        }
+        let git_ref = config.git_ref();
+        let selection = match config.pipeline() {
+            Some(name) => Some((name.to_string(), Reason::Override)),
+            None => rules
+                .into_iter()
+                .enumerate()
+                .find(|(_, r)| r.matches(git_ref))
+                .map(|(i, r)| match r.on {
+                    Some(_) => (r.pipeline, Reason::Rule(i + 1, git_ref.clone())),
+                    None => (r.pipeline, Reason::Fallback(i + 1)),
+                }),
+        };
+        let Some((name, reason)) = selection else {
+            println!("None of the pipelines are selected for {git_ref}");
+            return Ok((Self::default(), None));
+        };
+        let Some(pipeline) = pipelines.remove(&name) else {
+            return Err(Error::UndefinedPipeline(name));
+        };
+        let selection = Selection { name, reason };
+        println!("{selection}");
+        Ok((pipeline, Some(selection)))
+    }
+
+    /// Timeout for the whole pipeline.
+    pub fn timeout(&self) -> Result<Option<Duration>> {
+        let timeout = self.timeout.as_deref().map(duration::parse).transpose();
+        timeout.map_err(|e| Error::InvalidTimeout(String::from("pipeline"), e))
+    }
+
+    /// Whether the run is stopped on the first failure (see [crate::run::Run::start]).
+    pub fn fail_fast(&self) -> bool {
+        self.fail_fast.unwrap_or(true)
+    }
+
+    /// Resources shared by the running tasks (see [crate::run::Run::submit_runnable]).
+    pub fn capacity(&self) -> Result<Resources> {
+        let capacity = self.capacity.as_ref().map(RawResources::parse).transpose();
+        let capacity =
+            capacity.map_err(|e| Error::InvalidResources(String::from("pipeline"), e))?;
+        Ok(capacity.unwrap_or(Resources::UNLIMITED))
+    }
+
+    /// Retries for pulling the images.
+    fn pull_retry(&self) -> Result<Retry> {
+        retry(
+            self.pull_retries,
+            self.pull_retry_delay.as_deref(),
+            "image pulls",
+        )
+    }
+
     /// Obtain [Task]s.
     ///
     /// The difference to [RawTask]s is that each task gets assigned a unique
     /// [TaskId] which are used for dependencies (instead of task names).
-    /// In addition, missing images are replaced with default values.
-    pub fn tasks(self, default_image: Option<&str>) -> Result<HashMap<TaskId, Task>> {
-        let default_image = self.default_image.as_deref().or(default_image);
-        let id_map = TaskNames::from_tasks(&self.tasks, default_image)?;
+    /// In addition, missing images are replaced with default values and
+    /// pipeline-level environment variables are merged into each task
+    /// (task-level values take precedence).
+    ///
+    /// Tasks with a `stage` depend on all the tasks of the earlier stages
+    /// (see [Self::stage_depends]). Fails on dependency cycles, including the ones
+    /// through stages.
+    ///
+    /// `${...}` variables are replaced in the tasks before the images are de-duplicated
+    /// (see [Self::interpolate]).
+    ///
+    /// Before that, included files are merged into the pipeline (see [Self::include_files]),
+    /// templates are merged into the tasks that extend them,
+    /// tasks with a `matrix` are expanded into one task per combination
+    /// (see [Self::expand_matrices]) and tasks that are not run on the current ref
+    /// are dropped (see [Self::skipped_tasks]).
+    pub fn tasks(self, config: &PipelineConfig) -> Result<HashMap<TaskId, Task>> {
+        self.tasks_from(config, TaskId::first())
+    }
+
+    /// Obtain [Task]s (see [Self::tasks]) with the ids starting from `first_id`,
+    /// eg. for adding them into a run that already has tasks.
+    pub fn tasks_from(
+        mut self,
+        config: &PipelineConfig,
+        first_id: TaskId,
+    ) -> Result<HashMap<TaskId, Task>> {
+        if self.pipelines.is_some() || self.rules.is_some() {
+            let e = "named pipelines must be selected first";
+            return Err(Error::InvalidPipelines(String::from(e)));
+        }
+        self.include_files(config)?;
+        self.apply_templates()?;
+        self.expand_matrices()?;
+        self.validate()?;
+        let skipped = self.skipped_tasks(config)?;
+        for (name, reason) in skipped.iter() {
+            println!("Skipping task '{name}', {reason}");
+        }
+        self.tasks.retain(|t| !skipped.contains_key(&t.name));
+        self.interpolate(config)?;
+
+        let default_image = self.default_image.as_deref().or(config.default_image());
+        let id_map = TaskNames::from_tasks(&self.tasks, default_image, first_id)?;
+        let pull_retry = self.pull_retry()?;
+        let stage_depends = self.stage_depends(&id_map)?;
+        let capacity = self.capacity()?;
         let mut tasks = HashMap::new();
         for task in self.tasks.iter() {
-            let mut depends = TaskIds::default();
+            let mut depends = stage_depends.get(&task.name).copied().unwrap_or_default();
             for dep in task.depends.as_deref().unwrap_or_default() {
                 depends |= TaskIds::from(id_map.get_task_id(dep)?);
             }
@@ -46,25 +224,564 @@ impl RawPipeline {
                 depends |= TaskIds::from(image_id);
                 // only add image if its not already added
                 if let hash_map::Entry::Vacant(e) = tasks.entry(image_id) {
-                    e.insert(Task::PullImage(image_name.to_owned()));
+                    e.insert(Task::PullImage(image_name.to_owned(), pull_retry));
                 }
             }
             let id = id_map.get_task_id(&task.name)?;
             let task = Task::command(
                 task.name.to_owned(),
-                task.commands.to_owned(),
+                commands(task, config.repo_path())?,
                 image_name.map(String::from),
                 depends,
+                self.options(task, capacity)?,
             );
             tasks.insert(id, task);
         }
+        add_finalizer_depends(&mut tasks)?;
+        let locate = |name: &str| {
+            let task = self.tasks.iter().find(|t| t.name == name)?;
+            Some(task.location(None))
+        };
+        check_cycles(&tasks, locate)?;
         Ok(tasks)
     }
+
+    /// Implicit dependencies of the tasks with a `stage`, ie. all the tasks in earlier stages.
+    ///
+    /// Finalizers are run after all the other tasks, so they are not depended on.
+    fn stage_depends(&self, id_map: &TaskNames) -> Result<HashMap<String, TaskIds>> {
+        let stages = self.stages.as_deref().unwrap_or_default();
+        let mut stage_ids = vec![TaskIds::default(); stages.len()];
+        let mut task_stages = vec![];
+        for task in self.tasks.iter() {
+            let Some(stage) = &task.stage else {
+                continue;
+            };
+            let Some(ind) = stages.iter().position(|s| s == stage) else {
+                return Err(Error::UndefinedStage(task.name.clone(), stage.clone()));
+            };
+            if !task.when.unwrap_or_default().is_finalizer() {
+                stage_ids[ind] |= TaskIds::from(id_map.get_task_id(&task.name)?);
+            }
+            task_stages.push((task.name.clone(), ind));
+        }
+        let depends = task_stages
+            .into_iter()
+            .map(|(name, ind)| {
+                (
+                    name,
+                    stage_ids[..ind]
+                        .iter()
+                        .fold(TaskIds::default(), |a, b| a | *b),
+                )
+            })
+            .collect();
+        Ok(depends)
+    }
+
+    /// Validate and combine pipeline- and task-level settings of `task`.
+    /// The resources requested by the task must fit into the `capacity`.
+    fn options(&self, task: &RawTask, capacity: Resources) -> Result<TaskOptions> {
+        let mut env = self.env.clone().unwrap_or_default();
+        env.extend(task.env.clone().unwrap_or_default());
+        if let Some(var) = env.keys().find(|k| !is_valid_env_name(k)) {
+            return Err(Error::InvalidEnvName(var.to_owned()));
+        }
+        let when = task.when.unwrap_or_default();
+        let condition = match &task.condition {
+            Some(cond) => Some(
+                Condition::parse(cond, when.outcome())
+                    .map_err(|e| Error::InvalidCondition(task.name.clone(), e))?,
+            ),
+            None if when.is_finalizer() => Some(Condition::Outcome(when.outcome())),
+            None => None,
+        };
+        // with fail_fast, the run is stopped before the task could be run after a failure
+        let after_failure = condition
+            .as_ref()
+            .is_some_and(Condition::allows_failed_deps);
+        if after_failure && !when.is_finalizer() && self.fail_fast() {
+            let e = "failure() and always() need `when: always`, `when: on_failure` \
+                     or `fail_fast: false`";
+            return Err(Error::InvalidCondition(task.name.clone(), e.to_string()));
+        }
+        let shell = task.shell.as_ref().or(self.shell.as_ref());
+        let shell: Vec<_> = shell.iter().flat_map(|s| s.split_whitespace()).collect();
+        if shell.is_empty() && (task.shell.is_some() || self.shell.is_some()) {
+            return Err(Error::InvalidShell(task.name.clone()));
+        }
+        let timeout = task.timeout.as_deref().map(duration::parse).transpose();
+        let name = format!("task {}", task.name);
+        let manual = task.manual.unwrap_or_default();
+        let approval_name = format!("approval of task {}", task.name);
+        let approval_timeout = match task.approval_timeout.as_deref() {
+            Some(_) if !manual => {
+                let e = String::from("only manual tasks can have an approval_timeout");
+                return Err(Error::InvalidTimeout(approval_name, e));
+            }
+            t => t.map(duration::parse).transpose(),
+        };
+        let invalid_resources = |e| Error::InvalidResources(name.clone(), e);
+        let cpus = task.cpus.map(resources::parse_cpus).transpose();
+        let cpus = cpus.map_err(invalid_resources)?;
+        let memory = task
+            .memory
+            .as_deref()
+            .map(resources::parse_memory)
+            .transpose();
+        let memory = memory.map_err(invalid_resources)?;
+        let options = TaskOptions {
+            env,
+            condition,
+            when,
+            timeout: timeout.map_err(|e| Error::InvalidTimeout(name.clone(), e))?,
+            retry: retry(task.retries, task.retry_delay.as_deref(), &name)?,
+            allow_failure: task.allow_failure.unwrap_or_default(),
+            manual,
+            approval_timeout: approval_timeout
+                .map_err(|e| Error::InvalidTimeout(approval_name, e))?,
+            shell: shell.into_iter().map(String::from).collect(),
+            working_dir: task
+                .working_dir
+                .as_deref()
+                .map(relative_path)
+                .transpose()
+                .map_err(|e| Error::InvalidWorkingDir(task.name.clone(), e))?,
+            generates: task
+                .generates
+                .as_deref()
+                .map(relative_path)
+                .transpose()
+                .map_err(|e| Error::InvalidGenerates(task.name.clone(), e))?,
+            priority: task.priority.unwrap_or_default(),
+            cpus,
+            memory,
+        };
+        // the default request is limited to the capacity, only explicit requests must fit
+        let request = Resources {
+            millicpus: cpus.unwrap_or_default(),
+            memory: memory.unwrap_or_default(),
+        };
+        if !request.fits(capacity) {
+            let e = format!("requests {request}, but the capacity is {capacity}");
+            return Err(invalid_resources(e));
+        }
+        Ok(options)
+    }
+
+    /// Merge the tasks and templates of the included files into the pipeline.
+    ///
+    /// The includes are resolved relative to the repository root and the included files
+    /// can include further files. Other settings (eg. `env`) of the included files are ignored.
+    /// Includes outside of the repository and include cycles are rejected.
+    fn include_files(&mut self, config: &PipelineConfig) -> Result<()> {
+        self.set_source(config.pipeline_filename());
+        if self.include.is_none() {
+            return Ok(());
+        }
+        let repo_path = config.repo_path().canonicalize()?;
+        let path = repo_path.join(config.pipeline_filename());
+        let mut included_from = vec![path.canonicalize().unwrap_or(path)];
+        self.include_from(&repo_path, &mut included_from)
+    }
+
+    /// `included_from` contains the chain of files that lead to this file being included.
+    fn include_from(&mut self, repo_path: &Path, included_from: &mut Vec<PathBuf>) -> Result<()> {
+        let templates = self.templates.get_or_insert_default();
+        for include in self.include.take().unwrap_or_default() {
+            let invalid = |e: String| Error::InvalidInclude(include.clone(), e);
+            let path = repo_path
+                .join(&include)
+                .canonicalize()
+                .map_err(|e| invalid(e.to_string()))?;
+            let source = match path.strip_prefix(repo_path) {
+                Ok(p) => p.display().to_string(),
+                Err(_) => return Err(invalid(String::from("outside of the repository"))),
+            };
+            if included_from.contains(&path) {
+                let cycle: Vec<_> = included_from
+                    .iter()
+                    .chain([&path])
+                    .map(|p| p.strip_prefix(repo_path).unwrap_or(p).display().to_string())
+                    .collect();
+                return Err(invalid(format!("include cycle {}", cycle.join(" -> "))));
+            }
+            let src = fs::read_to_string(&path)?;
+            let mut included = RawPipeline::parse(&src).map_err(|e| invalid(e.to_string()))?;
+            included.set_source(&source);
+            included_from.push(path);
+            included.include_from(repo_path, included_from)?;
+            included_from.pop();
+
+            self.tasks.extend(included.tasks);
+            for (name, template) in included.templates.into_iter().flatten() {
+                match templates.entry(name) {
+                    btree_map::Entry::Vacant(e) => e.insert(template),
+                    btree_map::Entry::Occupied(e) => {
+                        return Err(Error::DuplicateTemplate(e.key().to_owned()));
+                    }
+                };
+            }
+        }
+        Ok(())
+    }
+
+    fn set_source(&mut self, source: &str) {
+        self.tasks
+            .iter_mut()
+            .for_each(|t| t.source = source.to_string());
+    }
+
+    /// Merge templates into the tasks that extend them (see [RawTemplate]).
+    /// All the templates are validated, even if they are not used.
+    fn apply_templates(&mut self) -> Result<()> {
+        let templates = self.templates.take().unwrap_or_default();
+        let resolved = templates
+            .keys()
+            .map(|name| Ok((name.as_str(), RawTemplate::resolve(name, &templates)?)))
+            .collect::<Result<HashMap<_, _>>>()?;
+        for task in self.tasks.iter_mut() {
+            if let Some(name) = task.extends.take() {
+                let Some(template) = resolved.get(name.as_str()) else {
+                    return Err(Error::UndefinedTemplate(name));
+                };
+                if template.commands.is_some() && task.script.is_some() {
+                    return Err(Error::InvalidCommands(
+                        task.name.clone(),
+                        format!("script is set, but template '{name}' sets commands"),
+                    ));
+                }
+                template.apply(task);
+            }
+        }
+        Ok(())
+    }
+
+    /// Replace tasks that have a `matrix` with their expansions. Dependencies on
+    /// the original task name are replaced with dependencies on all of the expanded tasks.
+    fn expand_matrices(&mut self) -> Result<()> {
+        let mut expanded_names = HashMap::new();
+        let mut tasks = vec![];
+        for task in self.tasks.drain(..) {
+            let Some(matrix) = &task.matrix else {
+                tasks.push(task);
+                continue;
+            };
+            let expanded = matrix.expand(&task)?;
+            let names: Vec<_> = expanded.iter().map(|t| t.name.clone()).collect();
+            expanded_names.insert(task.name, names);
+            tasks.extend(expanded);
+        }
+        for depends in tasks.iter_mut().filter_map(|t| t.depends.as_mut()) {
+            *depends = depends
+                .drain(..)
+                .flat_map(|d| expanded_names.get(&d).cloned().unwrap_or(vec![d]))
+                .collect();
+        }
+        self.tasks = tasks;
+        Ok(())
+    }
+
+    /// Replace `${...}` variables in `default_image`, `env` and the string fields of the tasks.
+    ///
+    /// Variables are resolved from the built-in `runr.` variables (see
+    /// [PipelineConfig::builtin_vars]), pipeline-level `variables` and `env` of the task
+    /// (merged with the pipeline-level `env`), in this order. `env` values can refer only
+    /// to the built-in and pipeline-level variables. Undefined variables are an error,
+    /// except in `commands`, which are shell: there only the built-in and pipeline-level
+    /// variables are replaced and other `${...}` are kept as they are.
+    fn interpolate(&mut self, config: &PipelineConfig) -> Result<()> {
+        let vars = self.variables.clone().unwrap_or_default();
+        let lookup = |name: &str| config.builtin_vars().get(name).or(vars.get(name)).cloned();
+        let invalid = |name: &str| {
+            let name = name.to_string();
+            move |e| Error::InvalidVariable(name, e)
+        };
+        for value in self.env.iter_mut().flat_map(|env| env.values_mut()) {
+            *value = interpolate(value, lookup).map_err(invalid("pipeline"))?;
+        }
+        if let Some(image) = &mut self.default_image {
+            *image = interpolate(image, lookup).map_err(invalid("pipeline"))?;
+        }
+        for task in self.tasks.iter_mut() {
+            let name = format!("task {}", task.name);
+            for value in task.env.iter_mut().flat_map(|env| env.values_mut()) {
+                *value = interpolate(value, lookup).map_err(invalid(&name))?;
+            }
+            let builtin = |var: &str| var.starts_with("runr.");
+            task.commands =
+                interpolate_known(&task.commands, lookup, builtin).map_err(invalid(&name))?;
+            let mut env = self.env.clone().unwrap_or_default();
+            env.extend(task.env.clone().unwrap_or_default());
+            let lookup = |name: &str| lookup(name).or_else(|| env.get(name).cloned());
+            let fields = [
+                &mut task.image,
+                &mut task.script,
+                &mut task.shell,
+                &mut task.working_dir,
+                &mut task.generates,
+            ];
+            for field in fields.into_iter().flatten() {
+                *field = interpolate(field, lookup).map_err(invalid(&name))?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Check that the task names are unique, the dependencies are defined and
+    /// that there are no dependency cycles, collecting all the errors.
+    fn validate(&self) -> Result<()> {
+        let mut errors = vec![];
+        let mut by_name: HashMap<&str, &RawTask> = HashMap::new();
+        for task in self.tasks.iter() {
+            let hash_map::Entry::Vacant(e) = by_name.entry(&task.name) else {
+                continue;
+            };
+            e.insert(task);
+            let sources: Vec<_> = self
+                .tasks
+                .iter()
+                .filter(|t| t.name == task.name)
+                .map(|t| t.location(None))
+                .collect();
+            if sources.len() > 1 {
+                errors.push(Error::DuplicateTask(task.name.clone(), sources));
+            }
+        }
+        for task in self.tasks.iter() {
+            for dep in task.depends.iter().flatten() {
+                if by_name.contains_key(dep.as_str()) {
+                    continue;
+                }
+                let suggestion = closest(dep, by_name.keys().copied()).map(String::from);
+                let e = Error::UndefinedDependency(task.name.clone(), dep.clone(), suggestion);
+                let at = task.location(task.locations.find("depends", dep));
+                errors.push(Error::Located(at, Box::new(e)));
+            }
+        }
+        let mut checked = HashSet::new();
+        for task in self.tasks.iter() {
+            let mut path = vec![];
+            if let Some(cycle) = visit(&task.name, &by_name, &mut checked, &mut path) {
+                let at = by_name[cycle[0].as_str()].location(None);
+                errors.push(Error::Located(at, Box::new(Error::DependencyCycle(cycle))));
+                // not to report the same cycle again starting from another task
+                checked.extend(path);
+            }
+        }
+        Error::collect(errors)
+    }
+
+    /// Names of the tasks that are not run (with the reason), ie.
+    /// * all tasks if the pipeline-level `on` does not match the ref
+    /// * tasks whose own `on` does not match the ref
+    /// * tasks with `changes` that do not match any of the changed files
+    ///   (if they are known, see [PipelineConfig::changed_files])
+    /// * tasks that (transitively) depend on a skipped task.
+    fn skipped_tasks(&self, config: &PipelineConfig) -> Result<BTreeMap<String, String>> {
+        let git_ref = config.git_ref();
+        let names: HashSet<_> = self.tasks.iter().map(|t| t.name.as_str()).collect();
+        let depends = |t: &RawTask| t.depends.clone().unwrap_or_default();
+        let matches = |f: &Option<RefFilter>| f.as_ref().is_none_or(|f| f.matches(git_ref));
+        let not_run = format!("not run on {git_ref}");
+        if !matches(&self.on) {
+            let skipped = names.into_iter().map(|n| (n.to_string(), not_run.clone()));
+            return Ok(skipped.collect());
+        }
+        let changed = |patterns: &[String]| match config.changed_files() {
+            Some(files) => files
+                .iter()
+                .any(|f| patterns.iter().any(|p| glob::matches(p, f))),
+            None => true,
+        };
+        let mut skipped = BTreeMap::new();
+        for task in self.tasks.iter() {
+            if !matches(&task.on) {
+                skipped.insert(task.name.clone(), not_run.clone());
+            } else if !task.changes.as_deref().is_none_or(changed) {
+                skipped.insert(task.name.clone(), String::from("no matching changes"));
+            }
+        }
+        loop {
+            let n_skipped = skipped.len();
+            for task in self.tasks.iter() {
+                if skipped.contains_key(&task.name) {
+                    continue;
+                }
+                if let Some(dep) = depends(task).iter().find(|d| skipped.contains_key(*d)) {
+                    let reason = format!("depends on skipped task '{dep}'");
+                    skipped.insert(task.name.clone(), reason);
+                }
+            }
+            if skipped.len() == n_skipped {
+                return Ok(skipped);
+            }
+        }
+    }
+}
+
+/// DFS over the dependencies by task names, returns the first cycle found from `name`
+/// (see [Error::DependencyCycle]). Undefined dependencies are skipped and the tasks
+/// on the `path` (from the task that the search was started from) are being checked.
+fn visit<'a>(
+    name: &'a str,
+    tasks: &HashMap<&str, &'a RawTask>,
+    checked: &mut HashSet<&'a str>,
+    path: &mut Vec<&'a str>,
+) -> Option<Vec<String>> {
+    if checked.contains(name) {
+        return None;
+    }
+    if let Some(start) = path.iter().position(|n| *n == name) {
+        let cycle = path[start..].iter().chain([&name]);
+        return Some(cycle.map(|n| n.to_string()).collect());
+    }
+    let task = tasks.get(name)?;
+    path.push(name);
+    for dep in task.depends.iter().flatten() {
+        if let Some(cycle) = visit(dep, tasks, checked, path) {
+            return Some(cycle);
+        }
+    }
+    path.pop();
+    checked.insert(name);
+    None
+}
+
+/// Report the key at the location of `err` as unknown if the schema does not allow it,
+/// with the allowed keys for suggestions. Other errors are reported as they are.
+fn unknown_key(src: &str, err: serde_yaml::Error) -> Error {
+    let unknown = err.location().and_then(|loc| {
+        let at = Location {
+            line: loc.line(),
+            column: loc.column(),
+        };
+        let (path, key) = location::key_at(src, at)?;
+        let expected = allowed_keys(&path)?;
+        (!expected.contains(&key)).then_some((path, key, expected, at))
+    });
+    let Some((path, key, expected, at)) = unknown else {
+        return Error::from(err);
+    };
+    let at = match path.is_empty() {
+        true => format!(" at line {} column {}", at.line, at.column),
+        false => format!(" in {path} at line {} column {}", at.line, at.column),
+    };
+    Error::UnknownKey(key, expected, at)
+}
+
+/// Make finalizers depend on all the other tasks so that they are run last.
+/// Consequently, the outcome of their dependencies is the outcome of the whole pipeline.
+///
+/// Fails if some other task depends on a finalizer.
+fn add_finalizer_depends(tasks: &mut HashMap<TaskId, Task>) -> Result<()> {
+    let finalizers: TaskIds = tasks
+        .iter()
+        .filter(|(_, t)| t.is_finalizer())
+        .map(|(id, _)| *id)
+        .collect();
+    let others: TaskIds = tasks.keys().copied().collect::<TaskIds>() & !finalizers;
+    for task in tasks.values() {
+        let finalizer_deps = task.depends() & finalizers;
+        if let Some(dep) = finalizer_deps.ids().next().filter(|_| !task.is_finalizer()) {
+            return Err(Error::FinalizerDependency(
+                task.name().to_string(),
+                tasks[&dep].name().to_string(),
+            ));
+        }
+    }
+    for task in tasks.values_mut().filter(|t| t.is_finalizer()) {
+        task.add_depends(others);
+    }
+    Ok(())
+}
+
+/// Commands of the task, either given inline or read from the `script` file.
+fn commands(task: &RawTask, repo_path: &Path) -> Result<String> {
+    let invalid = |e: &str| Error::InvalidCommands(task.name.clone(), e.to_string());
+    let Some(script) = &task.script else {
+        if task.commands.is_empty() {
+            return Err(invalid("either commands or script must be set"));
+        }
+        return Ok(task.commands.clone());
+    };
+    if !task.commands.is_empty() {
+        return Err(invalid("both commands and script are set"));
+    }
+    let repo_path = repo_path.canonicalize()?;
+    let path = repo_path
+        .join(script)
+        .canonicalize()
+        .map_err(|e| invalid(&format!("script '{script}': {e}")))?;
+    if !path.starts_with(&repo_path) {
+        return Err(invalid(&format!(
+            "script '{script}' is outside of the repository"
+        )));
+    }
+    fs::read_to_string(path).map_err(|e| invalid(&format!("script '{script}': {e}")))
+}
+
+/// Normalize `path`, which must be relative and stay within the directory it is relative to.
+fn relative_path(path: &str) -> std::result::Result<PathBuf, String> {
+    let mut normalized = PathBuf::new();
+    for component in Path::new(path).components() {
+        match component {
+            Component::Normal(c) => normalized.push(c),
+            Component::CurDir => {}
+            Component::ParentDir if normalized.pop() => {}
+            Component::ParentDir => return Err(format!("'{path}' is outside of the repository")),
+            Component::RootDir | Component::Prefix(_) => {
+                return Err(format!("'{path}' is not relative to the repository root"));
+            }
+        }
+    }
+    Ok(normalized)
+}
+
+/// `retries` and `delay` of `name`, which are unset by default.
+fn retry(retries: Option<u32>, delay: Option<&str>, name: &str) -> Result<Retry> {
+    let delay = delay.map(duration::parse).transpose();
+    Ok(Retry {
+        retries: retries.unwrap_or_default(),
+        delay: delay
+            .map_err(|e| Error::InvalidRetryDelay(name.to_string(), e))?
+            .unwrap_or_default(),
+    })
+}
+
+/// Environment variable names must be non-empty, consist of ascii
+/// alphanumerics and underscores and not start with a digit.
+fn is_valid_env_name(name: &str) -> bool {
+    let mut chars = name.chars();
+    match chars.next() {
+        Some(c) if c.is_ascii_alphabetic() || c == '_' => {}
+        _ => return false,
+    }
+    chars.all(|c| c.is_ascii_alphanumeric() || c == '_')
 }
 
 #[cfg(test)]
 mod test {
     use super::*;
+    use crate::config::GitRef;
+    use crate::pipeline::condition::Outcome;
+
+    fn config(default_image: Option<&str>) -> PipelineConfig {
+        let git_ref = GitRef::Branch(String::from("main"));
+        config_with(default_image, git_ref)
+    }
+
+    fn config_with(default_image: Option<&str>, git_ref: GitRef) -> PipelineConfig {
+        let image = default_image.map(String::from);
+        PipelineConfig::new(
+            image,
+            git_ref,
+            PathBuf::from("."),
+            String::from("runr.yaml"),
+            BTreeMap::new(),
+            None,
+        )
+    }
 
     #[test]
     fn parse_empty() {
@@ -74,6 +791,7 @@ mod test {
             default_image: None,
             n_parallel: None,
             tasks: vec![],
+            ..Default::default()
         };
         assert_eq!(raw_tasks, raw_tasks_exp)
     }
@@ -103,17 +821,20 @@ mod test {
             commands: String::from("echo\nexit 0\n"),
             image: None,
             depends: Some(vec![]),
+            ..Default::default()
         };
         let task2 = RawTask {
             name: String::from("n"),
             commands: String::from("echo n"),
             image: Some(String::from("image0")),
             depends: Some(vec![String::from("step-0"), String::from("step-1")]),
+            ..Default::default()
         };
         let tasks_exp = RawPipeline {
             default_image: Some(String::from("default-image")),
             n_parallel: Some(77),
             tasks: vec![task1, task2],
+            ..Default::default()
         };
         assert_eq!(raw_tasks, tasks_exp)
     }
@@ -127,7 +848,7 @@ mod test {
           depends: ["step-2"]
         "#;
         let raw_tasks: RawPipeline = serde_yaml::from_str(yaml).unwrap();
-        assert!(raw_tasks.tasks(Some("image")).is_err());
+        assert!(raw_tasks.tasks(&config(Some("image"))).is_err());
     }
 
     #[test]
@@ -140,6 +861,912 @@ mod test {
           name: "step-1"
         "#;
         let raw_tasks: RawPipeline = serde_yaml::from_str(yaml).unwrap();
-        assert!(raw_tasks.tasks(Some("imagez")).is_err());
+        assert!(raw_tasks.tasks(&config(Some("imagez"))).is_err());
+    }
+
+    #[test]
+    fn task_env_overrides_pipeline_env() {
+        let yaml = r#"
+        env:
+          A: pipeline
+          B: pipeline
+        tasks:
+        - commands: cmd
+          name: "step-1"
+          env:
+            B: task
+        "#;
+        let raw_tasks: RawPipeline = serde_yaml::from_str(yaml).unwrap();
+        let tasks = raw_tasks.tasks(&config(None)).unwrap();
+        let env = [("A", "pipeline"), ("B", "task")]
+            .into_iter()
+            .map(|(k, v)| (String::from(k), String::from(v)))
+            .collect();
+        let task_exp = Task::CommandLine {
+            name: String::from("step-1"),
+            commands: String::from("cmd"),
+            depends: TaskIds::default(),
+            options: TaskOptions {
+                env,
+                ..Default::default()
+            },
+        };
+        assert_eq!(tasks[&TaskId::first()], task_exp);
+    }
+
+    #[test]
+    fn invalid_env_names() {
+        for name in ["", "1A", "A-B", "A B", "Ä"] {
+            let yaml = format!(
+                r#"
+                env:
+                  "{name}": value
+                tasks:
+                - commands: cmd
+                  name: "step-1"
+                "#
+            );
+            let raw_tasks: RawPipeline = serde_yaml::from_str(&yaml).unwrap();
+            assert!(raw_tasks.tasks(&config(None)).is_err());
+        }
+    }
+
+    #[test]
+    fn tasks_are_filtered_by_ref() {
+        let yaml = r#"
+        tasks:
+        - commands: cmd
+          name: build
+        - commands: cmd
+          name: deploy
+          depends: [build]
+          on:
+            branches: ["main", "release/*"]
+        - commands: cmd
+          name: notify
+          depends: [deploy]
+        - commands: cmd
+          name: publish
+          on:
+            tags: ["v*"]
+        "#;
+        let run_on = |git_ref| {
+            let raw_tasks: RawPipeline = serde_yaml::from_str(yaml).unwrap();
+            let config = config_with(None, git_ref);
+            let mut names: Vec<_> = raw_tasks
+                .tasks(&config)
+                .unwrap()
+                .into_values()
+                .map(|t| t.to_string())
+                .collect();
+            names.sort();
+            names
+        };
+        let names = run_on(GitRef::Branch(String::from("release/1")));
+        assert_eq!(
+            names,
+            ["shell      build", "shell      deploy", "shell      notify"]
+        );
+        let names = run_on(GitRef::Branch(String::from("feature")));
+        assert_eq!(names, ["shell      build"]);
+        let names = run_on(GitRef::Tag(String::from("v1.0")));
+        assert_eq!(names, ["shell      build", "shell      publish"]);
+    }
+
+    #[test]
+    fn pipeline_filter_skips_everything() {
+        let yaml = r#"
+        on:
+          branches: [main]
+        tasks:
+        - commands: cmd
+          name: build
+        "#;
+        let raw_tasks: RawPipeline = serde_yaml::from_str(yaml).unwrap();
+        let config = config_with(None, GitRef::Branch(String::from("dev")));
+        assert!(raw_tasks.tasks(&config).unwrap().is_empty());
+    }
+
+    #[test]
+    fn undefined_dependency_is_detected_before_filtering() {
+        let yaml = r#"
+        tasks:
+        - commands: cmd
+          name: build
+          on:
+            branches: [main]
+        - commands: cmd
+          name: test
+          depends: [build, lint]
+        "#;
+        let raw_tasks = RawPipeline::parse(yaml).unwrap();
+        let config = config_with(None, GitRef::Branch(String::from("dev")));
+        assert_eq!(
+            raw_tasks.tasks(&config).unwrap_err().to_string(),
+            "runr.yaml:9:28: Task test depends on undefined task 'lint'"
+        );
+    }
+
+    #[test]
+    fn validation_errors_are_collected() {
+        let yaml = r#"
+        tasks:
+        - commands: cmd
+          name: build
+          depends: [tset]
+        - commands: cmd
+          name: test
+          depends: [lint]
+        - commands: cmd
+          name: lint
+          depends: [test]
+        - commands: cmd
+          name: build
+        "#;
+        let raw_tasks = RawPipeline::parse(yaml).unwrap();
+        let err = raw_tasks.tasks(&config(None)).unwrap_err();
+        assert!(matches!(&err, Error::InvalidPipeline(errors) if errors.len() == 3));
+        let expected = "Invalid pipeline, 3 errors:
+  - Task build defined multiple times:
+      - in runr.yaml:3:11
+      - in runr.yaml:12:11
+  - runr.yaml:5:21: Task build depends on undefined task 'tset', did you mean 'test'?
+  - runr.yaml:6:11: Dependency cycle: test -> lint -> test";
+        assert_eq!(err.to_string(), expected);
+    }
+
+    #[test]
+    fn matrix_is_expanded_with_dependencies_and_images() {
+        let yaml = r#"
+        tasks:
+        - commands: cargo test
+          name: test
+          image: "rust:${matrix.rust}"
+          matrix:
+            rust: ["1.91", "1.92"]
+        - commands: cargo build
+          name: build
+          image: "rust:1.91"
+        - commands: deploy
+          name: deploy
+          depends: [test]
+        "#;
+        let raw_tasks: RawPipeline = serde_yaml::from_str(yaml).unwrap();
+        let tasks = raw_tasks.tasks(&config(None)).unwrap();
+        // two images + three tasks + deploy
+        assert_eq!(tasks.len(), 6);
+        let deploy = tasks
+            .values()
+            .find(|t| t.to_string().ends_with("deploy"))
+            .unwrap();
+        let test_ids: TaskIds = tasks
+            .iter()
+            .filter(|(_, t)| t.to_string().contains("test (rust="))
+            .map(|(id, _)| *id)
+            .collect();
+        assert_eq!(test_ids.ids().count(), 2);
+        assert_eq!(deploy.depends(), test_ids);
+    }
+
+    #[test]
+    fn tasks_extend_templates() {
+        let yaml = r#"
+        templates:
+          base:
+            commands: echo setup
+            image: img
+        tasks:
+        - commands: echo test
+          name: test
+          extends: base
+        "#;
+        let raw_tasks: RawPipeline = serde_yaml::from_str(yaml).unwrap();
+        let tasks = raw_tasks.tasks(&config(None)).unwrap();
+        let task_exp = Task::Container {
+            name: String::from("test"),
+            commands: String::from("echo setup\necho test"),
+            image: String::from("img"),
+            depends: TaskIds::from(TaskId::first()),
+            options: TaskOptions::default(),
+        };
+        assert_eq!(tasks[&TaskId::try_from(1).unwrap()], task_exp);
+
+        let yaml = r#"
+        tasks:
+        - commands: echo test
+          name: test
+          extends: base
+        "#;
+        let raw_tasks: RawPipeline = serde_yaml::from_str(yaml).unwrap();
+        assert!(matches!(
+            raw_tasks.tasks(&config(None)),
+            Err(Error::UndefinedTemplate(_))
+        ));
+
+        let yaml = r#"
+        templates:
+          base:
+            commands: echo setup
+        tasks:
+        - script: ci/check.py
+          name: check
+          extends: base
+        "#;
+        let raw_tasks: RawPipeline = serde_yaml::from_str(yaml).unwrap();
+        assert!(matches!(
+            raw_tasks.tasks(&config(None)),
+            Err(Error::InvalidCommands(t, e)) if t == "check" && e.contains("'base'")
+        ));
+    }
+
+    /// Repository in a unique temporary directory, which is removed on drop.
+    /// The repository is in a subdirectory, so that files outside it can be written too.
+    struct TestRepo(PathBuf);
+
+    impl TestRepo {
+        fn path(&self) -> PathBuf {
+            self.0.join("repo")
+        }
+    }
+
+    impl Drop for TestRepo {
+        fn drop(&mut self) {
+            let _ = std::fs::remove_dir_all(&self.0);
+        }
+    }
+
+    fn write_repo(name: &str, files: &[(&str, &str)]) -> TestRepo {
+        static N_REPOS: std::sync::atomic::AtomicUsize = std::sync::atomic::AtomicUsize::new(0);
+        let n = N_REPOS.fetch_add(1, std::sync::atomic::Ordering::Relaxed);
+        let dir = format!("runr-include-test-{name}-{}-{n}", std::process::id());
+        let repo = TestRepo(std::env::temp_dir().join(dir));
+        for (path, content) in files {
+            let path = repo.path().join(path);
+            std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+            std::fs::write(path, content).unwrap();
+        }
+        repo
+    }
+
+    fn read_repo(repo: &TestRepo) -> Result<HashMap<TaskId, Task>> {
+        let repo_path = repo.path();
+        let yaml = std::fs::read_to_string(repo_path.join("runr.yaml")).unwrap();
+        let raw_tasks = RawPipeline::parse(&yaml).unwrap();
+        let git_ref = GitRef::Branch(String::from("main"));
+        let config = PipelineConfig::new(
+            None,
+            git_ref,
+            repo_path,
+            String::from("runr.yaml"),
+            BTreeMap::new(),
+            None,
+        );
+        raw_tasks.tasks(&config)
+    }
+
+    #[test]
+    fn included_tasks_are_merged() {
+        let main = r#"
+        include: ["ci/test.yaml"]
+        tasks:
+        - commands: cmd
+          name: build
+        "#;
+        let test = r#"
+        include: ["ci/lint.yaml"]
+        tasks:
+        - commands: cmd
+          name: test
+          depends: [build]
+        "#;
+        let lint = r#"
+        tasks:
+        - commands: cmd
+          name: lint
+        "#;
+        let files = [
+            ("runr.yaml", main),
+            ("ci/test.yaml", test),
+            ("ci/lint.yaml", lint),
+        ];
+        let tasks = read_repo(&write_repo("merged", &files)).unwrap();
+        assert_eq!(tasks.len(), 3);
+    }
+
+    #[test]
+    fn duplicates_cycles_and_escapes_are_rejected() {
+        let main = r#"
+        include: ["ci/test.yaml"]
+        tasks:
+        - commands: cmd
+          name: test
+        "#;
+        let test = r#"
+        tasks:
+        - commands: cmd
+          name: test
+        "#;
+        let files = [("runr.yaml", main), ("ci/test.yaml", test)];
+        match read_repo(&write_repo("duplicate", &files)) {
+            Err(Error::DuplicateTask(name, sources)) => {
+                assert_eq!(name, "test");
+                assert_eq!(sources, ["runr.yaml:4:11", "ci/test.yaml:3:11"]);
+            }
+            res => panic!("expected duplicate task, got {res:?}"),
+        }
+
+        let test = r#"
+        include: ["runr.yaml"]
+        tasks: []
+        "#;
+        let files = [("runr.yaml", main), ("ci/test.yaml", test)];
+        let res = read_repo(&write_repo("cycle", &files));
+        assert!(matches!(res, Err(Error::InvalidInclude(_, e)) if e.contains("cycle")));
+
+        let main = r#"
+        include: ["../runr.yaml"]
+        tasks: []
+        "#;
+        let files = [("runr.yaml", main), ("../runr.yaml", "tasks: []")];
+        let res = read_repo(&write_repo("escape", &files));
+        assert!(matches!(res, Err(Error::InvalidInclude(_, e)) if e.contains("outside")));
+    }
+
+    #[test]
+    fn invalid_condition_fails() {
+        let yaml = r#"
+        tasks:
+        - commands: cmd
+          name: deploy
+          if: branch = 'main'
+        "#;
+        let raw_tasks: RawPipeline = serde_yaml::from_str(yaml).unwrap();
+        assert!(matches!(
+            raw_tasks.tasks(&config(None)),
+            Err(Error::InvalidCondition(t, _)) if t == "deploy"
+        ));
+    }
+
+    #[test]
+    fn failure_conditions_need_finalizers_with_fail_fast() {
+        let task = |cond: &str, when: &str| {
+            format!("tasks:\n- {{commands: cmd, name: notify, if: \"{cond}\"{when}}}")
+        };
+        for cond in ["failure()", "always() && env.X", "!success()"] {
+            let yaml = task(cond, "");
+            let raw_tasks: RawPipeline = serde_yaml::from_str(&yaml).unwrap();
+            assert!(
+                matches!(
+                    raw_tasks.tasks(&config(None)),
+                    Err(Error::InvalidCondition(t, e)) if t == "notify" && e.contains("fail_fast")
+                ),
+                "{cond}"
+            );
+            for yaml in [
+                task(cond, ", when: always"),
+                format!("fail_fast: false\n{yaml}"),
+            ] {
+                let raw_tasks: RawPipeline = serde_yaml::from_str(&yaml).unwrap();
+                assert!(raw_tasks.tasks(&config(None)).is_ok(), "{yaml}");
+            }
+        }
+        let yaml = task("success() && env.X", "");
+        let raw_tasks: RawPipeline = serde_yaml::from_str(&yaml).unwrap();
+        assert!(raw_tasks.tasks(&config(None)).is_ok());
+    }
+
+    #[test]
+    fn finalizers_depend_on_other_tasks() {
+        let yaml = r#"
+        tasks:
+        - commands: cmd
+          name: test
+        - commands: cmd
+          name: teardown
+          when: always
+        - commands: cmd
+          name: notify
+          when: on_failure
+          depends: [teardown]
+        "#;
+        let raw_tasks: RawPipeline = serde_yaml::from_str(yaml).unwrap();
+        let tasks = raw_tasks.tasks(&config(None)).unwrap();
+        let [test, teardown, notify] = [0, 1, 2].map(|i| TaskId::try_from(i).unwrap());
+        assert_eq!(tasks[&teardown].depends(), TaskIds::from(test));
+        assert_eq!(
+            tasks[&notify].depends(),
+            [test, teardown].into_iter().collect()
+        );
+        let outcome = |id| tasks[&id].options().unwrap().condition.clone();
+        assert_eq!(outcome(test), None);
+        assert_eq!(outcome(teardown), Some(Condition::Outcome(Outcome::Always)));
+        assert_eq!(outcome(notify), Some(Condition::Outcome(Outcome::Failure)));
+
+        let yaml = r#"
+        tasks:
+        - commands: cmd
+          name: test
+          depends: [teardown]
+        - commands: cmd
+          name: teardown
+          when: always
+        "#;
+        let raw_tasks: RawPipeline = serde_yaml::from_str(yaml).unwrap();
+        assert!(matches!(
+            raw_tasks.tasks(&config(None)),
+            Err(Error::FinalizerDependency(t, f)) if t == "test" && f == "teardown"
+        ));
+    }
+
+    #[test]
+    fn timeouts_are_parsed() {
+        let yaml = r#"
+        timeout: 1h
+        tasks:
+        - commands: cmd
+          name: test
+          timeout: 10m
+        - commands: cmd
+          name: build
+        "#;
+        let raw_pipeline: RawPipeline = serde_yaml::from_str(yaml).unwrap();
+        assert_eq!(
+            raw_pipeline.timeout().unwrap(),
+            Some(Duration::from_secs(3600))
+        );
+        let tasks = raw_pipeline.tasks(&config(None)).unwrap();
+        let timeout = |i| {
+            tasks[&TaskId::try_from(i).unwrap()]
+                .options()
+                .unwrap()
+                .timeout
+        };
+        assert_eq!(timeout(0), Some(Duration::from_secs(600)));
+        assert_eq!(timeout(1), None);
+
+        let yaml = r#"
+        tasks:
+        - commands: cmd
+          name: test
+          timeout: 10 minutes
+        "#;
+        let raw_pipeline: RawPipeline = serde_yaml::from_str(yaml).unwrap();
+        assert!(matches!(
+            raw_pipeline.tasks(&config(None)),
+            Err(Error::InvalidTimeout(t, _)) if t == "task test"
+        ));
+    }
+
+    #[test]
+    fn approval_timeouts_need_manual_tasks() {
+        let yaml = r#"
+        tasks:
+        - commands: cmd
+          name: deploy
+          manual: true
+          approval_timeout: 1h
+        "#;
+        let raw_pipeline: RawPipeline = serde_yaml::from_str(yaml).unwrap();
+        let tasks = raw_pipeline.tasks(&config(None)).unwrap();
+        let options = tasks[&TaskId::try_from(0).unwrap()].options().unwrap();
+        assert!(options.manual);
+        assert_eq!(options.approval_timeout, Some(Duration::from_secs(3600)));
+
+        let yaml = r#"
+        tasks:
+        - commands: cmd
+          name: deploy
+          approval_timeout: 1h
+        "#;
+        let raw_pipeline: RawPipeline = serde_yaml::from_str(yaml).unwrap();
+        assert!(matches!(
+            raw_pipeline.tasks(&config(None)),
+            Err(Error::InvalidTimeout(t, _)) if t == "approval of task deploy"
+        ));
+    }
+
+    #[test]
+    fn retries_are_set_for_tasks_and_pulls() {
+        let yaml = r#"
+        pull_retries: 2
+        pull_retry_delay: 5s
+        tasks:
+        - commands: cmd
+          name: test
+          image: img
+          retries: 3
+          retry_delay: 1s
+        "#;
+        let raw_pipeline: RawPipeline = serde_yaml::from_str(yaml).unwrap();
+        let tasks = raw_pipeline.tasks(&config(None)).unwrap();
+        let [img, test] = [0, 1].map(|i| &tasks[&TaskId::try_from(i).unwrap()]);
+        let retry = |retries, secs| Retry {
+            retries,
+            delay: Duration::from_secs(secs),
+        };
+        assert_eq!(test.retry(), retry(3, 1));
+        assert_eq!(img.retry(), retry(2, 5));
+        assert_eq!(test.retry().delay(3), Duration::from_secs(4));
+
+        let yaml = r#"
+        pull_retry_delay: soon
+        tasks:
+        - commands: cmd
+          name: test
+          image: img
+        "#;
+        let raw_pipeline: RawPipeline = serde_yaml::from_str(yaml).unwrap();
+        assert!(matches!(
+            raw_pipeline.tasks(&config(None)),
+            Err(Error::InvalidRetryDelay(t, _)) if t == "image pulls"
+        ));
+    }
+
+    #[test]
+    fn scripts_are_read_and_shells_are_split() {
+        let main = r#"
+        shell: sh -e
+        tasks:
+        - script: ci/check.py
+          name: check
+          shell: python3
+        - commands: cmd
+          name: build
+        "#;
+        let files = [("runr.yaml", main), ("ci/check.py", "print('ok')\n")];
+        let tasks = read_repo(&write_repo("script", &files)).unwrap();
+        let [check, build] = [0, 1].map(|i| &tasks[&TaskId::try_from(i).unwrap()]);
+        let Task::CommandLine { commands, .. } = check else {
+            panic!("unexpected task {check:?}")
+        };
+        assert_eq!(commands, "print('ok')\n");
+        assert_eq!(check.options().unwrap().shell(), ["python3"]);
+        assert_eq!(build.options().unwrap().shell(), ["sh", "-e"]);
+
+        let invalid = [
+            ("both", "- {commands: cmd, script: ci/check.py, name: t}"),
+            ("neither", "- {name: t}"),
+            ("escape", "- {script: ../escape.py, name: t}"),
+            ("missing", "- {script: ci/missing.py, name: t}"),
+        ];
+        for (name, task) in invalid {
+            let yaml = format!("tasks:\n{task}");
+            let files = [
+                ("runr.yaml", yaml.as_str()),
+                ("ci/check.py", ""),
+                ("../escape.py", ""),
+            ];
+            let res = read_repo(&write_repo(&format!("script-{name}"), &files));
+            assert!(matches!(res, Err(Error::InvalidCommands(..))), "{name}");
+        }
+        let yaml = "tasks:\n- {commands: cmd, name: t, shell: ' '}";
+        let raw_tasks: RawPipeline = serde_yaml::from_str(yaml).unwrap();
+        assert!(matches!(
+            raw_tasks.tasks(&config(None)),
+            Err(Error::InvalidShell(t)) if t == "t"
+        ));
+    }
+
+    #[test]
+    fn working_dir_must_stay_in_repository() {
+        assert_eq!(
+            relative_path("./services/foo/../bar/"),
+            Ok(PathBuf::from("services/bar"))
+        );
+        assert_eq!(relative_path("."), Ok(PathBuf::new()));
+        for path in ["..", "services/../..", "/tmp"] {
+            assert!(relative_path(path).is_err(), "{path}");
+        }
+        let yaml = r#"
+        tasks:
+        - commands: cmd
+          name: build
+          working_dir: services/../../foo
+        "#;
+        let raw_tasks: RawPipeline = serde_yaml::from_str(yaml).unwrap();
+        assert!(matches!(
+            raw_tasks.tasks(&config(None)),
+            Err(Error::InvalidWorkingDir(t, _)) if t == "build"
+        ));
+    }
+
+    #[test]
+    fn variables_are_interpolated_before_images_are_deduplicated() {
+        let yaml = r#"
+        variables:
+          RUST_VERSION: "1.91"
+        env:
+          IMAGE: builder:${RUST_VERSION}
+        tasks:
+        - commands: echo ${runr.branch} $${HOME}
+          name: build
+          image: builder:${RUST_VERSION}
+        - commands: cmd
+          name: test
+          image: ${IMAGE}
+        "#;
+        let vars = [(String::from("runr.branch"), String::from("main"))].into();
+        let git_ref = GitRef::Branch(String::from("main"));
+        let config = PipelineConfig::new(
+            None,
+            git_ref,
+            PathBuf::from("."),
+            String::from("runr.yaml"),
+            vars,
+            None,
+        );
+        let raw_tasks: RawPipeline = serde_yaml::from_str(yaml).unwrap();
+        let tasks = raw_tasks.tasks(&config).unwrap();
+        assert_eq!(tasks.len(), 3);
+        let image = TaskId::try_from(0).unwrap();
+        assert_eq!(tasks[&image].name(), "builder:1.91");
+        let build = TaskId::try_from(1).unwrap();
+        let Task::Container { commands, .. } = &tasks[&build] else {
+            panic!("unexpected task {:?}", tasks[&build])
+        };
+        assert_eq!(commands, "echo main ${HOME}");
+
+        let yaml = r#"
+        variables:
+          TARGET: release
+        tasks:
+        - commands: echo ${HOME} ${CARGO:-cargo} ${TARGET}
+          name: build
+        "#;
+        let raw_tasks: RawPipeline = serde_yaml::from_str(yaml).unwrap();
+        let tasks = raw_tasks.tasks(&config).unwrap();
+        let Task::CommandLine { commands, .. } = &tasks[&TaskId::try_from(0).unwrap()] else {
+            panic!("unexpected task {tasks:?}")
+        };
+        assert_eq!(commands, "echo ${HOME} ${CARGO:-cargo} release");
+
+        let yaml = r#"
+        tasks:
+        - commands: echo ${runr.commit}
+          name: build
+        "#;
+        let raw_tasks: RawPipeline = serde_yaml::from_str(yaml).unwrap();
+        assert!(matches!(
+            raw_tasks.tasks(&config),
+            Err(Error::InvalidVariable(t, e)) if t == "task build" && e.contains("runr.commit")
+        ));
+    }
+
+    #[test]
+    fn tasks_depend_on_earlier_stages() {
+        let yaml = r#"
+        stages: [build, test, deploy]
+        tasks:
+        - commands: cmd
+          name: build
+          stage: build
+        - commands: cmd
+          name: lint
+        - commands: cmd
+          name: test
+          stage: test
+        - commands: cmd
+          name: deploy
+          stage: deploy
+          depends: [lint]
+        - commands: cmd
+          name: teardown
+          stage: build
+          when: always
+        "#;
+        let raw_tasks: RawPipeline = serde_yaml::from_str(yaml).unwrap();
+        let tasks = raw_tasks.tasks(&config(None)).unwrap();
+        let [build, lint, test, deploy] = [0, 1, 2, 3].map(|i| TaskId::try_from(i).unwrap());
+        assert_eq!(tasks[&build].depends(), TaskIds::default());
+        assert_eq!(tasks[&lint].depends(), TaskIds::default());
+        assert_eq!(tasks[&test].depends(), TaskIds::from(build));
+        assert_eq!(
+            tasks[&deploy].depends(),
+            [build, lint, test].into_iter().collect()
+        );
+
+        let yaml = r#"
+        stages: [build]
+        tasks:
+        - commands: cmd
+          name: test
+          stage: test
+        "#;
+        let raw_tasks: RawPipeline = serde_yaml::from_str(yaml).unwrap();
+        assert!(matches!(
+            raw_tasks.tasks(&config(None)),
+            Err(Error::UndefinedStage(t, s)) if t == "test" && s == "test"
+        ));
+    }
+
+    #[test]
+    fn tasks_are_filtered_by_changes() {
+        let yaml = r#"
+        tasks:
+        - commands: cmd
+          name: backend
+          changes: ["backend/**", "Cargo.lock"]
+        - commands: cmd
+          name: deploy-backend
+          depends: [backend]
+        - commands: cmd
+          name: frontend
+          changes: ["frontend/**"]
+        - commands: cmd
+          name: docs
+        "#;
+        let run_with = |changed_files: Option<Vec<&str>>| {
+            let config = PipelineConfig::new(
+                None,
+                GitRef::Branch(String::from("main")),
+                PathBuf::from("."),
+                String::from("runr.yaml"),
+                BTreeMap::new(),
+                changed_files.map(|fs| fs.into_iter().map(String::from).collect()),
+            );
+            let raw_tasks: RawPipeline = serde_yaml::from_str(yaml).unwrap();
+            let tasks = raw_tasks.tasks(&config).unwrap();
+            let mut names: Vec<_> = tasks.values().map(|t| t.name().to_string()).collect();
+            names.sort();
+            names
+        };
+        assert_eq!(
+            run_with(Some(vec!["frontend/src/main.ts", "README.md"])),
+            ["docs", "frontend"]
+        );
+        assert_eq!(
+            run_with(Some(vec!["Cargo.lock"])),
+            ["backend", "deploy-backend", "docs"]
+        );
+        // unknown changes, eg. a new branch
+        assert_eq!(run_with(None).len(), 4);
+    }
+
+    #[test]
+    fn pipelines_are_selected_by_rules_or_explicitly() {
+        let yaml = r#"
+        pipelines:
+          feature:
+            tasks:
+            - commands: cargo test
+              name: test
+          release:
+            tasks:
+            - commands: cargo publish
+              name: publish
+        rules:
+        - pipeline: release
+          on:
+            tags: ["v*"]
+        - pipeline: feature
+          on:
+            branches: ["feature/*"]
+        "#;
+        let select = |config: &PipelineConfig| {
+            let raw_pipeline: RawPipeline = serde_yaml::from_str(yaml).unwrap();
+            raw_pipeline.select(config).unwrap()
+        };
+        let tag = GitRef::Tag(String::from("v1.0"));
+        let (pipeline, selection) = select(&config_with(None, tag.clone()));
+        assert_eq!(pipeline.tasks[0].name, "publish");
+        let reason = Reason::Rule(1, tag);
+        assert_eq!(selection.unwrap().reason, reason);
+
+        let (pipeline, selection) = select(&config(None));
+        assert_eq!(pipeline, RawPipeline::default());
+        assert_eq!(selection, None);
+
+        let (pipeline, selection) = select(&config(None).with_pipeline(String::from("feature")));
+        assert_eq!(pipeline.tasks[0].name, "test");
+        let selection = selection.unwrap();
+        assert_eq!(selection.reason, Reason::Override);
+        assert_eq!(
+            selection.to_string(),
+            "Selected pipeline 'feature' explicitly"
+        );
+
+        let raw_pipeline: RawPipeline = serde_yaml::from_str(yaml).unwrap();
+        let config = config(None).with_pipeline(String::from("nightly"));
+        assert!(matches!(
+            raw_pipeline.select(&config),
+            Err(Error::UndefinedPipeline(n)) if n == "nightly"
+        ));
+    }
+
+    #[test]
+    fn pipelines_are_validated() {
+        let invalid = |yaml: &str| {
+            let raw_pipeline: RawPipeline = serde_yaml::from_str(yaml).unwrap();
+            raw_pipeline.select(&config(None)).unwrap_err()
+        };
+        let yaml = r#"
+        pipelines:
+          main:
+            tasks: []
+        rules:
+        - pipeline: main
+        - pipeline: nightly
+        "#;
+        assert!(matches!(invalid(yaml), Error::UndefinedPipeline(n) if n == "nightly"));
+
+        let yaml = r#"
+        pipelines:
+          main:
+            tasks: []
+        tasks:
+        - commands: cmd
+          name: test
+        "#;
+        assert!(matches!(invalid(yaml), Error::InvalidPipelines(_)));
+
+        let yaml = r#"
+        rules:
+        - pipeline: main
+        tasks: []
+        "#;
+        assert!(matches!(invalid(yaml), Error::InvalidPipelines(_)));
+    }
+
+    #[test]
+    fn rules_without_on_match_any_ref() {
+        let yaml = r#"
+        pipelines:
+          main:
+            tasks: []
+          default:
+            tasks: []
+        rules:
+        - pipeline: main
+          on:
+            branches: [main]
+        - pipeline: default
+        "#;
+        let raw_pipeline: RawPipeline = serde_yaml::from_str(yaml).unwrap();
+        let config = config_with(None, GitRef::Branch(String::from("feature")));
+        let (_, selection) = raw_pipeline.select(&config).unwrap();
+        let selection = selection.unwrap();
+        assert_eq!(selection.name, "default");
+        assert_eq!(selection.reason, Reason::Fallback(2));
+    }
+
+    #[test]
+    fn unknown_keys_are_rejected_with_suggestions() {
+        let yaml = r#"
+        tasks:
+        - commands: cmd
+          name: test
+          depend: [build]
+        "#;
+        let err = RawPipeline::parse(yaml);
+        let Err(err @ Error::UnknownKey(..)) = err else {
+            panic!("unexpected {err:?}");
+        };
+        assert_eq!(
+            err.to_string(),
+            "Unknown key 'depend' in tasks[0] at line 5 column 11, did you mean 'depends'?"
+        );
+
+        let yaml = "n_paralel: 2\ntasks: []";
+        let err = RawPipeline::parse(yaml);
+        assert!(matches!(err, Err(Error::UnknownKey(k, _, _)) if k == "n_paralel"));
+
+        let yaml = "tasks: []\nfoo: bar";
+        let err = RawPipeline::parse(yaml).unwrap_err().to_string();
+        assert!(
+            err.contains("expected one of 'capacity', 'default_image', "),
+            "{err}"
+        );
+
+        let yaml = "pipelines:\n  main:\n    tasks: []\n    capacity: {cpu: 2}";
+        let err = RawPipeline::parse(yaml).unwrap_err().to_string();
+        assert_eq!(
+            err,
+            "Unknown key 'cpu' in pipelines.main.capacity at line 4 column 16, did you mean 'cpus'?"
+        );
+
+        // other errors are not reported as unknown keys
+        let yaml = "tasks:\n- {name: test, commands: cmd, depends: build}";
+        let err = RawPipeline::parse(yaml);
+        assert!(matches!(err, Err(Error::Io(_))), "{err:?}");
     }
 }
@@ -1,9 +1,68 @@
-#[derive(Debug, serde::Deserialize, PartialEq)]
+use super::location::{Location, Locations};
+use super::matrix::Matrix;
+use super::ref_filter::RefFilter;
+use super::task::When;
+use std::collections::BTreeMap;
+
+#[derive(Clone, Debug, Default, schemars::JsonSchema, serde::Deserialize, PartialEq)]
+#[serde(deny_unknown_fields)]
 pub struct RawTask {
     pub name: String,
+    pub extends: Option<String>,
+    #[serde(default)]
     pub commands: String,
+    /// File containing the commands, relative to the repository root. Cannot be combined
+    /// with `commands`, including the ones from the template the task extends.
+    pub script: Option<String>,
+    pub shell: Option<String>,
+    /// Directory to run the commands in, relative to the repository root.
+    pub working_dir: Option<String>,
     pub image: Option<String>,
     pub depends: Option<Vec<String>>,
+    /// Stage of the task, which must be listed in the pipeline-level `stages`.
+    pub stage: Option<String>,
+    pub env: Option<BTreeMap<String, String>>,
+    pub on: Option<RefFilter>,
+    /// Glob patterns, the task is run only if some of the changed files match.
+    pub changes: Option<Vec<String>>,
+    pub matrix: Option<Matrix>,
+    #[serde(rename = "if")]
+    pub condition: Option<String>,
+    pub when: Option<When>,
+    pub timeout: Option<String>,
+    pub retries: Option<u32>,
+    pub retry_delay: Option<String>,
+    pub allow_failure: Option<bool>,
+    /// Wait for an approval (with `runr approve`) before running the task.
+    pub manual: Option<bool>,
+    /// Skip the manual task if it is not approved in time.
+    pub approval_timeout: Option<String>,
+    /// File (relative to the repository root) the task writes a pipeline to,
+    /// whose tasks are added to the run after the task succeeds.
+    pub generates: Option<String>,
+    /// Tasks with a higher priority (and their dependencies) are started first, defaults to 0.
+    pub priority: Option<i32>,
+    /// Number of CPUs the task needs, eg. `2` or `0.5`, defaults to 1.
+    pub cpus: Option<f64>,
+    /// Amount of memory the task needs, eg. `512m` or `2g`.
+    pub memory: Option<String>,
+    /// File the task is defined in, relative to the repository root.
+    #[serde(skip)]
+    pub source: String,
+    /// Locations of the nodes of the task in the [Self::source].
+    #[serde(skip)]
+    pub locations: Locations,
+}
+
+impl RawTask {
+    /// Location of a node of the task for errors, eg. `runr.yaml:3:5`.
+    /// Falls back to the location of the task itself and then to the file.
+    pub fn location(&self, node: Option<Location>) -> String {
+        match node.or(self.locations.get("")) {
+            Some(location) => format!("{}:{location}", self.source),
+            None => self.source.clone(),
+        }
+    }
 }
 
 #[cfg(test)]
@@ -24,6 +83,7 @@ mod test {
             commands: String::from("echo cmd && echo moi"),
             image: Some(String::from("image")),
             depends: Some(vec![String::from("step0"), String::from("step1")]),
+            ..Default::default()
         };
         assert_eq!(task, task_exp)
     }
@@ -42,7 +102,25 @@ mod test {
             commands: String::from("cmd\nexit 0\n"),
             image: None,
             depends: None,
+            ..Default::default()
         };
         assert_eq!(task, task_exp)
     }
+
+    #[test]
+    fn parse_env() {
+        let task_yaml = r#"
+            name: with-env
+            commands: echo $A
+            env:
+              A: "1"
+              B: b
+        "#;
+        let task: RawTask = serde_yaml::from_str(task_yaml).unwrap();
+        let env = [("A", "1"), ("B", "b")]
+            .into_iter()
+            .map(|(k, v)| (String::from(k), String::from(v)))
+            .collect();
+        assert_eq!(task.env, Some(env))
+    }
 }
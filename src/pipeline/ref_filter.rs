@@ -0,0 +1,47 @@
+use super::glob;
+use crate::config::GitRef;
+
+/// Glob patterns for the branches and tags a pipeline or a task is run on.
+///
+/// A branch (tag) matches only if `branches` (`tags`) is set and
+/// at least one of the patterns matches it.
+#[derive(Clone, Debug, Default, schemars::JsonSchema, serde::Deserialize, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct RefFilter {
+    pub branches: Option<Vec<String>>,
+    pub tags: Option<Vec<String>>,
+}
+
+impl RefFilter {
+    pub fn matches(&self, git_ref: &GitRef) -> bool {
+        let (patterns, name) = match git_ref {
+            GitRef::Branch(branch) => (&self.branches, branch),
+            GitRef::Tag(tag) => (&self.tags, tag),
+        };
+        patterns.iter().flatten().any(|p| glob::matches(p, name))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn branches_and_tags_are_matched_separately() {
+        let yaml = r#"
+            branches: ["main", "release/*"]
+        "#;
+        let filter: RefFilter = serde_yaml::from_str(yaml).unwrap();
+        assert!(filter.matches(&GitRef::Branch("main".into())));
+        assert!(filter.matches(&GitRef::Branch("release/1.0".into())));
+        assert!(!filter.matches(&GitRef::Branch("feature".into())));
+        assert!(!filter.matches(&GitRef::Tag("main".into())));
+
+        let filter = RefFilter {
+            branches: None,
+            tags: Some(vec!["v*".into()]),
+        };
+        assert!(filter.matches(&GitRef::Tag("v1.0".into())));
+        assert!(!filter.matches(&GitRef::Branch("v1.0".into())));
+    }
+}
@@ -0,0 +1,165 @@
+use std::{fmt, ops};
+
+/// CPUs (in thousandths) and memory (in bytes) requested by a task,
+/// or the capacity of the machine for running the tasks.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct Resources {
+    pub millicpus: u64,
+    pub memory: u64,
+}
+
+impl Resources {
+    pub const UNLIMITED: Self = Self {
+        millicpus: u64::MAX,
+        memory: u64::MAX,
+    };
+
+    /// Check if the resources fit into the `available` ones.
+    pub fn fits(self, available: Self) -> bool {
+        self.millicpus <= available.millicpus && self.memory <= available.memory
+    }
+
+    /// The resources limited to `other`, eg. to the capacity.
+    pub fn min(self, other: Self) -> Self {
+        Self {
+            millicpus: self.millicpus.min(other.millicpus),
+            memory: self.memory.min(other.memory),
+        }
+    }
+}
+
+impl ops::Add for Resources {
+    type Output = Self;
+
+    fn add(self, rhs: Self) -> Self {
+        Self {
+            millicpus: self.millicpus.saturating_add(rhs.millicpus),
+            memory: self.memory.saturating_add(rhs.memory),
+        }
+    }
+}
+
+impl ops::Sub for Resources {
+    type Output = Self;
+
+    fn sub(self, rhs: Self) -> Self {
+        Self {
+            millicpus: self.millicpus.saturating_sub(rhs.millicpus),
+            memory: self.memory.saturating_sub(rhs.memory),
+        }
+    }
+}
+
+impl fmt::Display for Resources {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.millicpus {
+            u64::MAX => write!(f, "any cpus")?,
+            m => write!(f, "{} cpus", cpus_arg(m))?,
+        }
+        match self.memory {
+            u64::MAX => write!(f, " and any memory"),
+            m => write!(f, " and {m} bytes of memory"),
+        }
+    }
+}
+
+/// Resources as written in the pipeline, see [Resources].
+#[derive(Clone, Debug, Default, schemars::JsonSchema, serde::Deserialize, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct RawResources {
+    /// Number of CPUs, eg. `2` or `0.5`.
+    pub cpus: Option<f64>,
+    /// Amount of memory, eg. `512m` or `2g`.
+    pub memory: Option<String>,
+}
+
+impl RawResources {
+    /// Unset resources are unlimited.
+    pub fn parse(&self) -> Result<Resources, String> {
+        Ok(Resources {
+            millicpus: self.cpus.map(parse_cpus).transpose()?.unwrap_or(u64::MAX),
+            memory: self
+                .memory
+                .as_deref()
+                .map(parse_memory)
+                .transpose()?
+                .unwrap_or(u64::MAX),
+        })
+    }
+}
+
+/// Parse the number of CPUs into thousandths of a CPU.
+pub fn parse_cpus(cpus: f64) -> Result<u64, String> {
+    let millicpus = (cpus * 1000.0).round();
+    if !millicpus.is_finite() || millicpus < 1.0 || millicpus > u32::MAX.into() {
+        return Err(format!("invalid number of cpus '{cpus}'"));
+    }
+    Ok(millicpus as u64)
+}
+
+/// Parse amounts of memory such as `512m` or `2g` into bytes.
+/// Supported units are `b`, `k`, `m` and `g` (as with podman).
+pub fn parse_memory(s: &str) -> Result<u64, String> {
+    let invalid = || format!("invalid memory '{s}', expected eg. 512m or 2g");
+    let s = s.trim();
+    let n_digits = s.find(|c: char| !c.is_ascii_digit()).unwrap_or(s.len());
+    let value: u64 = s[..n_digits].parse().map_err(|_| invalid())?;
+    let unit = match s[n_digits..].to_ascii_lowercase().as_str() {
+        "" | "b" => 1,
+        "k" => 1 << 10,
+        "m" => 1 << 20,
+        "g" => 1 << 30,
+        _ => return Err(invalid()),
+    };
+    match value.checked_mul(unit) {
+        Some(0) | None => Err(invalid()),
+        Some(bytes) => Ok(bytes),
+    }
+}
+
+/// Thousandths of a CPU as a decimal number for `podman run --cpus`.
+pub fn cpus_arg(millicpus: u64) -> String {
+    let cpus = format!("{}.{:03}", millicpus / 1000, millicpus % 1000);
+    cpus.trim_end_matches('0').trim_end_matches('.').to_string()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn resources_are_parsed() {
+        assert_eq!(parse_cpus(2.0), Ok(2000));
+        assert_eq!(parse_cpus(0.25), Ok(250));
+        for cpus in [0.0, -1.0, f64::NAN, f64::INFINITY] {
+            assert!(parse_cpus(cpus).is_err(), "{cpus}");
+        }
+        assert_eq!(parse_memory("512m"), Ok(512 << 20));
+        assert_eq!(parse_memory("2G"), Ok(2 << 30));
+        assert_eq!(parse_memory("1024"), Ok(1024));
+        for s in ["", "m", "0", "1.5g", "10x", "-1g"] {
+            assert!(parse_memory(s).is_err(), "{s}");
+        }
+        assert_eq!(cpus_arg(2000), "2");
+        assert_eq!(cpus_arg(1500), "1.5");
+        assert_eq!(cpus_arg(250), "0.25");
+    }
+
+    #[test]
+    fn resources_fit_into_the_available_ones() {
+        let capacity = RawResources {
+            cpus: Some(4.0),
+            memory: None,
+        };
+        let capacity = capacity.parse().unwrap();
+        let task = Resources {
+            millicpus: 3000,
+            memory: 1 << 30,
+        };
+        assert!(task.fits(capacity));
+        assert!(!(task + task).fits(capacity));
+        assert!(task.fits(capacity - task + task));
+        assert_eq!((task + task).min(capacity).millicpus, 4000);
+        assert_eq!(capacity.to_string(), "4 cpus and any memory");
+    }
+}
@@ -0,0 +1,50 @@
+use super::ref_filter::RefFilter;
+use crate::config::GitRef;
+use std::fmt;
+
+/// Rule for selecting one of the named `pipelines` based on the pushed ref.
+#[derive(Clone, Debug, Default, schemars::JsonSchema, serde::Deserialize, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct Rule {
+    pub pipeline: String,
+    /// Matches any ref if unset.
+    pub on: Option<RefFilter>,
+}
+
+impl Rule {
+    pub fn matches(&self, git_ref: &GitRef) -> bool {
+        self.on.as_ref().is_none_or(|f| f.matches(git_ref))
+    }
+}
+
+/// Named pipeline that was selected and why.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Selection {
+    pub name: String,
+    pub reason: Reason,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum Reason {
+    /// Selected explicitly, see [crate::PipelineConfig::pipeline].
+    Override,
+    /// The rule (starting from 1) matches the ref.
+    Rule(usize, GitRef),
+    /// The rule (starting from 1) has no `on`, so it matches any ref.
+    Fallback(usize),
+}
+
+impl fmt::Display for Selection {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = &self.name;
+        match &self.reason {
+            Reason::Override => write!(f, "Selected pipeline '{name}' explicitly"),
+            Reason::Rule(i, git_ref) => {
+                write!(f, "Selected pipeline '{name}', rule {i} matches {git_ref}")
+            }
+            Reason::Fallback(i) => {
+                write!(f, "Selected pipeline '{name}', rule {i} matches any ref")
+            }
+        }
+    }
+}
@@ -1,14 +1,21 @@
 use super::TaskIds;
+use super::condition::{Condition, Outcome};
+use super::resources::Resources;
 use crate::config::RunConfig;
-use crate::container_command::ContainerCommand;
-use crate::err::Result;
+use crate::container_command::{ContainerCommand, kill_container};
+use crate::err::{Error, Result};
+use std::collections::BTreeMap;
 use std::io::{BufRead, BufReader, PipeWriter, Write};
-use std::path::Path;
+use std::os::unix::process::CommandExt;
+use std::path::{Path, PathBuf};
 use std::process::{Child, Command, ExitStatus, Stdio};
-use std::sync::{Arc, Mutex};
-use std::{fmt, io};
+use std::sync::{Arc, Mutex, mpsc};
+use std::time::Duration;
+use std::{fmt, io, thread};
 
 const SHELL: &str = "/bin/bash";
+/// How often a task whose output is closed is checked for having exited.
+const EXIT_POLL_INTERVAL: Duration = Duration::from_millis(10);
 
 #[derive(Clone, Debug, PartialEq)]
 pub enum Task {
@@ -16,14 +23,108 @@ pub enum Task {
         name: String,
         commands: String,
         depends: TaskIds,
+        options: TaskOptions,
     },
     Container {
         name: String,
         commands: String,
         image: String,
         depends: TaskIds,
+        options: TaskOptions,
     },
-    PullImage(String),
+    PullImage(String, Retry),
+}
+
+/// Settings shared by [Task::CommandLine] and [Task::Container].
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct TaskOptions {
+    pub env: BTreeMap<String, String>,
+    /// Run only if the condition holds, defaults to all dependencies succeeding.
+    pub condition: Option<Condition>,
+    pub when: When,
+    /// Kill the task if it does not finish in time.
+    pub timeout: Option<Duration>,
+    pub retry: Retry,
+    /// Failure of the task does not fail the run.
+    pub allow_failure: bool,
+    /// Wait for an approval before running the task.
+    pub manual: bool,
+    /// Skip the task if it is not approved in time.
+    pub approval_timeout: Option<Duration>,
+    /// Interpreter and its arguments, the commands are given to it via stdin.
+    pub shell: Vec<String>,
+    /// Directory to run the commands in, relative to the repository root.
+    pub working_dir: Option<PathBuf>,
+    /// Pipeline written by the task, relative to the repository root.
+    pub generates: Option<PathBuf>,
+    /// Tasks with a higher priority are started first.
+    pub priority: i32,
+    /// Thousandths of the CPUs the task needs, see [Self::resources].
+    pub cpus: Option<u64>,
+    /// Bytes of memory the task needs.
+    pub memory: Option<u64>,
+}
+
+impl TaskOptions {
+    /// Resources reserved for the task while it runs, one CPU unless specified otherwise.
+    pub fn resources(&self) -> Resources {
+        Resources {
+            millicpus: self.cpus.unwrap_or(1000),
+            memory: self.memory.unwrap_or_default(),
+        }
+    }
+
+    /// Interpreter for the commands, defaults to bash.
+    pub fn shell(&self) -> Vec<&str> {
+        match self.shell.is_empty() {
+            true => vec![SHELL],
+            false => self.shell.iter().map(String::as_str).collect(),
+        }
+    }
+}
+
+/// How many times a failed task is re-run.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct Retry {
+    pub retries: u32,
+    /// Delay before the first retry, doubled for each subsequent retry.
+    pub delay: Duration,
+}
+
+impl Retry {
+    /// Delay after the given (failed) attempt, starting from 1.
+    pub fn delay(self, attempt: u32) -> Duration {
+        self.delay
+            .saturating_mul(2u32.saturating_pow(attempt.saturating_sub(1)))
+    }
+}
+
+/// When the task is run with respect to the other tasks of the pipeline.
+///
+/// Tasks with `always` and `on_failure` are finalizers, which are run only after
+/// all the other tasks are completed (or cancelled because of a failure).
+#[derive(Clone, Copy, Debug, Default, PartialEq, schemars::JsonSchema, serde::Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum When {
+    #[default]
+    OnSuccess,
+    Always,
+    OnFailure,
+}
+
+impl When {
+    pub fn is_finalizer(self) -> bool {
+        self != When::OnSuccess
+    }
+
+    /// Default outcome for the condition of the task.
+    pub fn outcome(self) -> Outcome {
+        match self {
+            When::OnSuccess => Outcome::Success,
+            When::Always => Outcome::Always,
+            When::OnFailure => Outcome::Failure,
+        }
+    }
 }
 
 impl Task {
@@ -32,6 +133,7 @@ impl Task {
         commands: String,
         image: Option<String>,
         depends: TaskIds,
+        options: TaskOptions,
     ) -> Self {
         if let Some(image) = image {
             return Self::Container {
@@ -39,12 +141,14 @@ impl Task {
                 commands,
                 image,
                 depends,
+                options,
             };
         }
         Self::CommandLine {
             name,
             commands,
             depends,
+            options,
         }
     }
 
@@ -52,7 +156,44 @@ impl Task {
         match self {
             Task::CommandLine { depends, .. } => *depends,
             Task::Container { depends, .. } => *depends,
-            Task::PullImage(_) => TaskIds::default(),
+            Task::PullImage(..) => TaskIds::default(),
+        }
+    }
+
+    pub fn add_depends(&mut self, task_ids: TaskIds) {
+        match self {
+            Task::CommandLine { depends, .. } => *depends |= task_ids,
+            Task::Container { depends, .. } => *depends |= task_ids,
+            Task::PullImage(..) => {}
+        }
+    }
+
+    pub fn is_finalizer(&self) -> bool {
+        self.options().is_some_and(|o| o.when.is_finalizer())
+    }
+
+    pub fn options(&self) -> Option<&TaskOptions> {
+        match self {
+            Task::CommandLine { options, .. } => Some(options),
+            Task::Container { options, .. } => Some(options),
+            Task::PullImage(..) => None,
+        }
+    }
+
+    pub fn retry(&self) -> Retry {
+        match self {
+            Task::CommandLine { options, .. } => options.retry,
+            Task::Container { options, .. } => options.retry,
+            Task::PullImage(_, retry) => *retry,
+        }
+    }
+
+    /// Name of the task, or the image for [Task::PullImage].
+    pub fn name(&self) -> &str {
+        match self {
+            Task::CommandLine { name, .. } => name,
+            Task::Container { name, .. } => name,
+            Task::PullImage(image, _) => image,
         }
     }
 
@@ -60,69 +201,210 @@ impl Task {
         match self {
             Task::CommandLine { name, .. } => Some(name.len()),
             Task::Container { name, .. } => Some(name.len()),
-            Task::PullImage(_) => None,
+            Task::PullImage(..) => None,
+        }
+    }
+
+    /// Print `msg` with the same prefix as the output of the task.
+    pub fn log(&self, config: &RunConfig, msg: impl fmt::Display) {
+        let width = config.name_width();
+        match self {
+            Task::PullImage(..) => println!("{msg}"),
+            _ => println!("{:width$}| {msg}", self.name()),
         }
     }
 
     /// Run the task.
-    /// `CommandLine`-task sets `in_progress` to the container name while the process is running.
+    ///
+    /// `in_progress` is set to the process (or container) while it is running,
+    /// and the process is killed if the timeout of the task expires. The process is
+    /// reaped only while holding the lock of `in_progress` and the handle is cleared
+    /// at the same time, so that the handle never refers to a process (group) whose
+    /// id may have been reused. Once the shell exits, the processes it left running
+    /// in its process group are killed before it is reaped.
     pub fn run(
         &self,
         config: &RunConfig,
-        in_progress: Arc<Mutex<Option<String>>>,
+        in_progress: Arc<Mutex<Option<Running>>>,
     ) -> Result<ExitStatus> {
         let (output_reader, output) = io::pipe()?;
-        match self {
-            Task::CommandLine { name, commands, .. } => {
-                let mut child = spawn_cmd(output, config.repo_path())?;
+        let (mut child, running, timeout) = match self {
+            Task::CommandLine {
+                commands, options, ..
+            } => {
+                let shell = options.shell();
+                let dir = config
+                    .repo_path()
+                    .join(options.working_dir.as_deref().unwrap_or(Path::new("")));
+                let mut child = spawn_cmd(output, &dir, &shell, &options.env)?;
                 write!(child.stdin.take().expect("run stdin taken"), "{commands}")?;
-                let width = config.name_width();
-                for line in BufReader::new(output_reader).lines() {
-                    println!("{name:width$}| {}", line?);
-                }
-                let status = child.wait()?;
-                Ok(status)
+                let running = Running::ProcessGroup(child.id());
+                (child, running, options.timeout)
             }
             Task::Container {
                 name,
                 commands,
                 image,
+                options,
                 ..
             } => {
-                let container_name = &config.mk_container_name(name);
+                let container_name = config.mk_container_name(name);
                 let cmd = ContainerCommand::Run {
                     commands,
                     image,
-                    container_name,
+                    shell: &options.shell(),
+                    working_dir: options.working_dir.as_deref(),
+                    env: &options.env,
+                    millicpus: options.cpus,
+                    memory: options.memory,
+                    container_name: &container_name,
                     config,
                 };
-                *in_progress.lock().unwrap() = Some(container_name.clone());
-                let mut child = cmd.start(output)?;
-                let width = config.name_width();
-                for line in BufReader::new(output_reader).lines() {
-                    println!("{name:width$}| {}", line?);
-                }
-                let status = child.wait()?;
-                *in_progress.lock().unwrap() = None;
-                Ok(status)
+                let child = cmd.start(output)?;
+                (child, Running::Container(container_name), options.timeout)
+            }
+            Task::PullImage(img, _) => {
+                let child = ContainerCommand::Pull(img).start(output)?;
+                let running = Running::Process(child.id());
+                (child, running, None)
+            }
+        };
+        *in_progress.lock().unwrap() = Some(running.clone());
+        let watchdog = timeout.map(|t| Watchdog::start(t, in_progress.clone()));
+        for line in BufReader::new(output_reader).lines() {
+            self.log(config, line?);
+        }
+        let status = loop {
+            let mut running = in_progress.lock().unwrap();
+            if let Some(Running::ProcessGroup(_)) = *running
+                && has_exited(&child)?
+            {
+                kill_and_wait(running.take().expect("process group set"))?;
             }
-            Task::PullImage(img) => {
-                let mut child = ContainerCommand::Pull(img).start(output)?;
-                for line in BufReader::new(output_reader).lines() {
-                    println!("{}", line?);
-                }
-                Ok(child.wait()?)
+            if let Some(status) = child.try_wait()? {
+                *running = None;
+                break status;
             }
+            drop(running);
+            thread::sleep(EXIT_POLL_INTERVAL);
+        };
+        match (watchdog.map(Watchdog::stop), timeout) {
+            (Some(true), Some(timeout)) => Err(Error::TimedOut(timeout)),
+            _ => Ok(status),
+        }
+    }
+}
+
+/// Handle for killing a running task.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Running {
+    Container(String),
+    Process(u32),
+    /// Process group of the shell of the task, for killing also the processes it started.
+    ProcessGroup(u32),
+}
+
+impl Running {
+    /// Start killing the task, the resulting `Child` must be waited.
+    pub fn kill(self, output: PipeWriter) -> Result<Child> {
+        match self {
+            Running::Container(name) => kill_container(name, output),
+            Running::Process(pid) => Ok(Command::new("kill")
+                .args(["-KILL", &pid.to_string()])
+                .stdout(output.try_clone()?)
+                .stderr(output)
+                .spawn()?),
+            Running::ProcessGroup(pgid) => Ok(Command::new("kill")
+                .args(["-KILL", "--", &format!("-{pgid}")])
+                .stdout(output.try_clone()?)
+                .stderr(output)
+                .spawn()?),
         }
     }
+
+    /// Whether the task is a process (group), which must not be reaped before it is
+    /// killed (see [Task::run]). Containers are killed by name.
+    pub fn is_process(&self) -> bool {
+        !matches!(self, Running::Container(_))
+    }
+}
+
+/// Kills the task unless it is stopped before the timeout.
+struct Watchdog {
+    done: mpsc::Sender<()>,
+    thread: thread::JoinHandle<bool>,
+}
+
+impl Watchdog {
+    /// The task is killed through `in_progress` (under its lock for processes, see
+    /// [Task::run]), so that it is not killed after it is reaped.
+    fn start(timeout: Duration, in_progress: Arc<Mutex<Option<Running>>>) -> Self {
+        let (done, receiver) = mpsc::channel();
+        let thread = thread::spawn(move || {
+            let Err(mpsc::RecvTimeoutError::Timeout) = receiver.recv_timeout(timeout) else {
+                return false;
+            };
+            let mut guard = in_progress.lock().unwrap();
+            let Some(running) = guard.take() else {
+                return false;
+            };
+            if !running.is_process() {
+                drop(guard);
+            }
+            if let Err(e) = kill_and_wait(running) {
+                eprintln!("unable to kill timed out task: {e}");
+            }
+            true
+        });
+        Self { done, thread }
+    }
+
+    /// Stop the watchdog, returns `true` if the task was killed.
+    fn stop(self) -> bool {
+        // the receiver is gone if the task was already killed
+        let _ = self.done.send(());
+        self.thread.join().unwrap_or(false)
+    }
+}
+
+fn kill_and_wait(running: Running) -> Result<()> {
+    let (output_reader, output) = io::pipe()?;
+    let mut child = running.kill(output)?;
+    for line in BufReader::new(output_reader).lines() {
+        println!("{}", line?);
+    }
+    child.wait()?;
+    Ok(())
+}
+
+/// Check if `child` has exited without reaping it, so that its id is not reused meanwhile.
+fn has_exited(child: &Child) -> Result<bool> {
+    // SAFETY: siginfo_t is plain data, for which all zeros is valid
+    let mut info: libc::siginfo_t = unsafe { std::mem::zeroed() };
+    let options = libc::WEXITED | libc::WNOHANG | libc::WNOWAIT;
+    // SAFETY: info is a valid siginfo_t, waitid does not keep the pointer
+    if unsafe { libc::waitid(libc::P_PID, child.id(), &mut info, options) } == -1 {
+        return Err(io::Error::last_os_error().into());
+    }
+    // SAFETY: waitid sets si_pid, which stays 0 if the child has not exited
+    Ok(unsafe { info.si_pid() } != 0)
 }
 
-fn spawn_cmd(output: PipeWriter, repo_path: &Path) -> Result<Child> {
-    Ok(Command::new(SHELL)
-        .current_dir(repo_path)
+/// Spawn the shell in its own process group (see [Running::ProcessGroup]).
+fn spawn_cmd(
+    output: PipeWriter,
+    dir: &Path,
+    shell: &[&str],
+    env: &BTreeMap<String, String>,
+) -> Result<Child> {
+    Ok(Command::new(shell[0])
+        .args(&shell[1..])
+        .current_dir(dir)
+        .envs(env)
         .stdout(output.try_clone()?)
         .stderr(output)
         .stdin(Stdio::piped())
+        .process_group(0)
         .spawn()?)
 }
 
@@ -131,7 +413,7 @@ impl fmt::Display for Task {
         let (cmd, name) = match self {
             Task::CommandLine { name, .. } => ("shell", name),
             Task::Container { name, .. } => ("container", name),
-            Task::PullImage(name) => ("pull", name),
+            Task::PullImage(name, _) => ("pull", name),
         };
         write!(f, "{cmd:10} {name}")
     }
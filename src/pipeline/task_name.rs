@@ -10,13 +10,21 @@ pub enum TaskName<'a> {
 }
 
 /// Used for obtaining unique identifiers for each [super::Task].
+///
+/// Expects tasks with a `matrix` to be expanded already, so that each
+/// combination gets its own id and identical images are pulled only once.
 #[derive(Debug)]
 pub struct TaskNames<'a>(HashMap<TaskName<'a>, TaskId>);
 
 impl<'a> TaskNames<'a> {
-    pub fn from_tasks(raw_tasks: &'a [RawTask], default_img: Option<&'a str>) -> Result<Self> {
+    /// Ids are assigned in order, starting from `first_id`.
+    pub fn from_tasks(
+        raw_tasks: &'a [RawTask],
+        default_img: Option<&'a str>,
+        first_id: TaskId,
+    ) -> Result<Self> {
         let mut id_map = HashMap::new();
-        let mut id = TaskId::first();
+        let mut id = first_id;
         for raw_task in raw_tasks.iter() {
             if let Some(image) = raw_task.image.as_deref().or(default_img)
                 && let hash_map::Entry::Vacant(e) = id_map.entry(TaskName::Image(image))
@@ -25,7 +33,12 @@ impl<'a> TaskNames<'a> {
             }
             let task_name = TaskName::Task(&raw_task.name);
             if id_map.contains_key(&task_name) {
-                return Err(Error::DuplicateTask(raw_task.name.clone()));
+                let sources = raw_tasks
+                    .iter()
+                    .filter(|t| t.name == raw_task.name)
+                    .map(|t| t.location(None))
+                    .collect();
+                return Err(Error::DuplicateTask(raw_task.name.clone(), sources));
             }
             id_map.insert(task_name, id.fetch_incr()?);
         }
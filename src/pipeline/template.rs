@@ -0,0 +1,172 @@
+use super::raw_task::RawTask;
+use super::ref_filter::RefFilter;
+use crate::err::{Error, Result};
+use std::collections::BTreeMap;
+
+/// Reusable (partial) task definition that tasks and other templates can `extends`.
+///
+/// When a template is merged into a task (or another template)
+/// * `commands` of the template are run before the commands of the task
+/// * `depends` of both are combined
+/// * `env` of both are combined, values of the task take precedence
+/// * `image` and `on` of the task take precedence.
+#[derive(Clone, Debug, Default, schemars::JsonSchema, serde::Deserialize, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct RawTemplate {
+    pub extends: Option<String>,
+    pub commands: Option<String>,
+    pub image: Option<String>,
+    pub depends: Option<Vec<String>>,
+    pub env: Option<BTreeMap<String, String>>,
+    pub on: Option<RefFilter>,
+}
+
+impl RawTemplate {
+    /// Resolve the template `name` by merging all the templates it (transitively) extends.
+    pub fn resolve(name: &str, templates: &BTreeMap<String, RawTemplate>) -> Result<Self> {
+        let mut chain = vec![];
+        let mut next = Some(name);
+        while let Some(name) = next {
+            if chain.contains(&name) {
+                chain.push(name);
+                return Err(Error::TemplateCycle(
+                    chain.into_iter().map(String::from).collect(),
+                ));
+            }
+            let Some(template) = templates.get(name) else {
+                return Err(Error::UndefinedTemplate(name.to_string()));
+            };
+            chain.push(name);
+            next = template.extends.as_deref();
+        }
+        let resolved = chain
+            .into_iter()
+            .rev()
+            .fold(Self::default(), |parent, name| {
+                parent.merge(&templates[name])
+            });
+        Ok(resolved)
+    }
+
+    /// Merge `child` into `self`.
+    fn merge(self, child: &Self) -> Self {
+        let commands = match (self.commands, &child.commands) {
+            (Some(cmds), Some(child_cmds)) => Some(concat(&cmds, child_cmds)),
+            (cmds, child_cmds) => child_cmds.clone().or(cmds),
+        };
+        Self {
+            extends: None,
+            commands,
+            image: child.image.clone().or(self.image),
+            depends: union(self.depends, &child.depends),
+            env: merge_env(self.env, &child.env),
+            on: child.on.clone().or(self.on),
+        }
+    }
+
+    /// Merge the template into `task`.
+    pub fn apply(&self, task: &mut RawTask) {
+        if let Some(cmds) = &self.commands {
+            task.commands = concat(cmds, &task.commands);
+        }
+        task.image = task.image.take().or(self.image.clone());
+        task.depends = union(self.depends.clone(), &task.depends);
+        task.env = merge_env(self.env.clone(), &task.env);
+        task.on = task.on.take().or(self.on.clone());
+    }
+}
+
+fn concat(first: &str, second: &str) -> String {
+    match first.ends_with('\n') || first.is_empty() {
+        true => format!("{first}{second}"),
+        false => format!("{first}\n{second}"),
+    }
+}
+
+fn union(depends: Option<Vec<String>>, other: &Option<Vec<String>>) -> Option<Vec<String>> {
+    let Some(mut depends) = depends else {
+        return other.clone();
+    };
+    for dep in other.iter().flatten() {
+        if !depends.contains(dep) {
+            depends.push(dep.clone());
+        }
+    }
+    Some(depends)
+}
+
+fn merge_env(
+    env: Option<BTreeMap<String, String>>,
+    other: &Option<BTreeMap<String, String>>,
+) -> Option<BTreeMap<String, String>> {
+    let Some(mut env) = env else {
+        return other.clone();
+    };
+    env.extend(other.clone().unwrap_or_default());
+    Some(env)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn templates() -> BTreeMap<String, RawTemplate> {
+        let yaml = r#"
+            base:
+              image: debian
+              commands: |
+                set -e
+              env:
+                A: base
+                B: base
+            rust:
+              extends: base
+              image: rust
+              commands: cargo fetch
+              depends: [setup]
+              env:
+                B: rust
+        "#;
+        serde_yaml::from_str(yaml).unwrap()
+    }
+
+    #[test]
+    fn templates_are_merged_into_task() {
+        let templates = templates();
+        let yaml = r#"
+            name: test
+            extends: rust
+            commands: cargo test
+            depends: [lint]
+            env:
+              C: task
+        "#;
+        let mut task: RawTask = serde_yaml::from_str(yaml).unwrap();
+        RawTemplate::resolve("rust", &templates)
+            .unwrap()
+            .apply(&mut task);
+        assert_eq!(task.commands, "set -e\ncargo fetch\ncargo test");
+        assert_eq!(task.image.as_deref(), Some("rust"));
+        let depends = vec![String::from("setup"), String::from("lint")];
+        assert_eq!(task.depends, Some(depends));
+        let env = [("A", "base"), ("B", "rust"), ("C", "task")]
+            .into_iter()
+            .map(|(k, v)| (String::from(k), String::from(v)))
+            .collect();
+        assert_eq!(task.env, Some(env));
+    }
+
+    #[test]
+    fn undefined_templates_and_cycles_fail() {
+        let mut templates = templates();
+        assert!(matches!(
+            RawTemplate::resolve("nope", &templates),
+            Err(Error::UndefinedTemplate(_))
+        ));
+        templates.get_mut("base").unwrap().extends = Some(String::from("rust"));
+        assert!(matches!(
+            RawTemplate::resolve("rust", &templates),
+            Err(Error::TemplateCycle(c)) if c == ["rust", "base", "rust"]
+        ));
+    }
+}
@@ -1,13 +1,29 @@
-use crate::config::RunConfig;
-use crate::container_command::kill_container;
-use crate::err::Result;
-use crate::pipeline::{Task, TaskId};
+use crate::approval::Approvals;
+use crate::config::{PipelineConfig, RunConfig};
+use crate::err::{Error, Result};
+use crate::history::History;
+use crate::pipeline::{Context, Resources, Running, Task, TaskId, TaskIds, read_generated};
 use crate::status::Status;
 use crate::worker::{WorkInput, WorkOutput, Worker};
-use std::collections::HashMap;
+use signal_hook::consts::{SIGINT, SIGTERM};
+use signal_hook::iterator::Signals;
+use std::collections::{HashMap, HashSet};
+use std::fs::File;
 use std::io::{BufRead, BufReader};
+use std::path::PathBuf;
 use std::sync::{Arc, Mutex, mpsc};
-use std::{fmt, io};
+use std::time::{Duration, Instant};
+use std::{fmt, io, process, thread};
+
+/// How often the approvals of the pending manual tasks are checked.
+const APPROVAL_POLL_INTERVAL: Duration = Duration::from_secs(1);
+
+/// How often the signals are checked while waiting for the workers.
+const SIGNAL_POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+/// Exit code when the run is cancelled with a signal, same as for shells interrupted
+/// with SIGINT.
+pub const CANCELLED: i32 = 130;
 
 #[derive(Debug)]
 pub struct Run {
@@ -15,12 +31,52 @@ pub struct Run {
     workers: Vec<Worker>,
     sender: mpsc::Sender<WorkInput>,
     receiver: mpsc::Receiver<WorkOutput>,
+    /// For interrupting the run, see [Self::cancel_on_signals]. Separate from the
+    /// workers' channel, so that the run notices if all the workers are gone.
+    interrupt: mpsc::Sender<i32>,
+    interrupted: mpsc::Receiver<i32>,
     tasks: HashMap<TaskId, Task>,
+    finalizers: TaskIds,
+    allowed_failures: TaskIds,
+    manual: TaskIds,
+    /// Pipelines written by the tasks, see [Self::add_generated].
+    generates: HashMap<TaskId, PathBuf>,
+    /// When the pending manual tasks started waiting for an approval.
+    waiting_since: HashMap<TaskId, Instant>,
+    /// Names of the running tasks and when they were started, for recording their durations.
+    started: HashMap<TaskId, (String, Instant)>,
+    /// Durations of the tasks for scheduling, see [Status::next_runnable_if].
+    history: History,
+    approvals: Option<Approvals>,
+    names: HashMap<TaskId, String>,
+    /// Names of the (non-pull) tasks, which must be unique also for the generated tasks.
+    task_names: HashSet<String>,
+    last_id: Option<TaskId>,
+    timeout: Option<Duration>,
+    timed_out: bool,
+    cancelled: bool,
+    /// Stop on the first failure, see [Self::start].
+    fail_fast: bool,
+    /// Resources shared by the running tasks, see [Self::submit_runnable].
+    capacity: Resources,
+    /// Resources reserved by the running tasks.
+    reserved: HashMap<TaskId, Resources>,
+    config: Arc<RunConfig>,
+    pipeline_config: PipelineConfig,
 }
 
 impl Run {
     /// Initialize run by setting up communication channels and initializing the workers.
-    pub fn new(n_workers: usize, config: RunConfig, tasks: HashMap<TaskId, Task>) -> Self {
+    pub fn new(
+        n_workers: usize,
+        config: RunConfig,
+        pipeline_config: PipelineConfig,
+        tasks: HashMap<TaskId, Task>,
+        timeout: Option<Duration>,
+        fail_fast: bool,
+        capacity: Resources,
+    ) -> Self {
+        let history = History::load(config.repo_name());
         let config = Arc::new(config);
         let (task_sender, task_receiver) = mpsc::channel();
         let (result_sender, result_receiver) = mpsc::channel();
@@ -28,16 +84,130 @@ impl Run {
         let workers = (0..n_workers)
             .map(|_| Worker::new(task_receiver.clone(), result_sender.clone(), config.clone()))
             .collect();
-        let deps = tasks.iter().map(|(id, t)| (*id, t.depends())).collect();
-        Self {
-            status: Status::new(deps),
+        let (interrupt, interrupted) = mpsc::channel();
+        let mut run = Self {
+            status: Status::new(vec![]),
             workers,
             sender: task_sender,
             receiver: result_receiver,
-            tasks,
+            interrupt,
+            interrupted,
+            tasks: HashMap::new(),
+            finalizers: TaskIds::default(),
+            allowed_failures: TaskIds::default(),
+            manual: TaskIds::default(),
+            generates: HashMap::new(),
+            waiting_since: HashMap::new(),
+            started: HashMap::new(),
+            history,
+            approvals: None,
+            names: HashMap::new(),
+            task_names: HashSet::new(),
+            last_id: None,
+            timeout,
+            timed_out: false,
+            cancelled: false,
+            fail_fast,
+            capacity,
+            reserved: HashMap::new(),
+            config,
+            pipeline_config,
+        };
+        run.add_tasks(tasks);
+        run
+    }
+
+    /// Add the tasks to the run as unstarted.
+    ///
+    /// The tasks are weighted by their durations in the earlier runs (see [History]),
+    /// tasks that have not been run yet are expected to take the mean duration.
+    /// Without any durations, all the tasks have the same weight.
+    fn add_tasks(&mut self, tasks: HashMap<TaskId, Task>) {
+        let mean = self.history.mean();
+        for (id, task) in tasks {
+            let duration = self.history.get(task.name()).or(mean);
+            let cost = duration.map_or(1, |d| u64::try_from(d.as_millis()).unwrap_or(u64::MAX));
+            let priority = task.options().map_or(0, |o| o.priority);
+            let ids = TaskIds::from(id);
+            if task.is_finalizer() {
+                self.finalizers |= ids;
+            }
+            if let Some(options) = task.options() {
+                if options.allow_failure {
+                    self.allowed_failures |= ids;
+                }
+                if options.manual {
+                    self.manual |= ids;
+                }
+                if let Some(path) = &options.generates {
+                    self.generates.insert(id, path.clone());
+                }
+                self.task_names.insert(task.name().to_string());
+            }
+            self.names.insert(id, task.to_string());
+            self.status.add([(id, task.depends())]);
+            self.status.set_weight(id, cost.max(1), priority);
+            self.last_id = self.last_id.max(Some(id));
+            self.tasks.insert(id, task);
         }
     }
 
+    /// Add the tasks of the pipeline written by the task `generator` (if any) to the run.
+    ///
+    /// The generated tasks depend on the generator, the finalizers of the run are run after
+    /// the generated tasks and the generated finalizers after all the other tasks.
+    fn add_generated(&mut self, generator: TaskId) -> Result<()> {
+        let Some(path) = self.generates.get(&generator) else {
+            return Ok(());
+        };
+        let config = self
+            .pipeline_config
+            .with_pipeline_filename(path.display().to_string());
+        let file = File::open(self.config.repo_path().join(path))?;
+        let first_id = match self.last_id {
+            Some(mut id) => {
+                id.fetch_incr()?;
+                id
+            }
+            None => TaskId::first(),
+        };
+        let tasks = read_generated(file, &config, first_id, generator)?;
+        let duplicate = tasks
+            .values()
+            .find(|t| t.options().is_some() && self.task_names.contains(t.name()));
+        if let Some(task) = duplicate {
+            let sources = vec![String::from("the run"), config.pipeline_filename().into()];
+            return Err(Error::DuplicateTask(task.name().to_string(), sources));
+        }
+        let ids: TaskIds = tasks.keys().copied().collect();
+        let finalizers: TaskIds = tasks
+            .iter()
+            .filter(|(_, t)| t.is_finalizer())
+            .map(|(id, _)| *id)
+            .collect();
+        let others = self.names.keys().copied().collect::<TaskIds>() & !self.finalizers;
+        println!(
+            "Adding {} tasks generated by {}",
+            ids.ids().count(),
+            self.names[&generator]
+        );
+        self.status.add_depends(self.finalizers, ids & !finalizers);
+        self.add_tasks(tasks);
+        self.status.add_depends(finalizers, others);
+        self.watch_approvals(ids & self.manual)
+    }
+
+    /// Allow approving the given manual tasks.
+    fn watch_approvals(&mut self, task_ids: TaskIds) -> Result<()> {
+        let names: Vec<_> = task_ids.ids().map(|id| self.tasks[&id].name()).collect();
+        match &mut self.approvals {
+            _ if names.is_empty() => {}
+            Some(approvals) => approvals.add(&names)?,
+            None => self.approvals = Some(Approvals::create(self.config.run_id(), &names)?),
+        }
+        Ok(())
+    }
+
     pub fn is_completed(&self) -> bool {
         self.status.is_completed()
     }
@@ -46,75 +216,392 @@ impl Run {
         self.status.is_succeeded()
     }
 
-    /// Check for new output
-    pub fn check_output(&self) -> Result<WorkOutput> {
-        Ok(self.receiver.recv()?)
+    /// Check if the run was stopped because the pipeline timed out, or some task
+    /// timed out without its failure being allowed.
+    pub fn is_timed_out(&self) -> bool {
+        self.timed_out || self.status.any_timed_out()
     }
 
-    /// Submit all runnable tasks
+    /// Check if the run was stopped by a signal, see [Self::cancel_on_signals].
+    pub fn is_cancelled(&self) -> bool {
+        self.cancelled
+    }
+
+    /// Cancel the run on SIGINT or SIGTERM: no more tasks are started and the running ones
+    /// are killed (see [Self::start]), after which the run should be cleaned up as usual.
+    ///
+    /// If another signal is received before the process exits, the running tasks are
+    /// killed without waiting for the containers to stop and the process exits immediately with [CANCELLED].
+    pub fn cancel_on_signals(&self) -> Result<()> {
+        let mut signals = Signals::new([SIGINT, SIGTERM])?;
+        let interrupt = self.interrupt.clone();
+        let running: Vec<_> = self.workers.iter().map(Worker::running).collect();
+        thread::spawn(move || {
+            let mut signals = signals.forever();
+            if let Some(signal) = signals.next() {
+                let _ = interrupt.send(signal);
+            }
+            if signals.next().is_some() {
+                eprintln!("Received another signal, killing running tasks and exiting.");
+                kill_now(running);
+                process::exit(CANCELLED);
+            }
+        });
+        Ok(())
+    }
+
+    /// Check for new output, returns `None` if nothing was received before the `deadline`.
+    ///
+    /// Signals received meanwhile (see [Self::cancel_on_signals]) are returned
+    /// as [WorkOutput::Interrupted].
+    pub fn check_output(&self, deadline: Option<Instant>) -> Result<Option<WorkOutput>> {
+        loop {
+            if let Ok(signal) = self.interrupted.try_recv() {
+                return Ok(Some(WorkOutput::Interrupted(signal)));
+            }
+            let poll = Instant::now() + SIGNAL_POLL_INTERVAL;
+            let wait_until = deadline.map_or(poll, |d| d.min(poll));
+            let timeout = wait_until.saturating_duration_since(Instant::now());
+            match self.receiver.recv_timeout(timeout) {
+                Ok(output) => return Ok(Some(output)),
+                Err(mpsc::RecvTimeoutError::Timeout) if Some(wait_until) == deadline => {
+                    return Ok(None);
+                }
+                Err(mpsc::RecvTimeoutError::Timeout) => continue,
+                Err(mpsc::RecvTimeoutError::Disconnected) => Err(mpsc::RecvError)?,
+            }
+        }
+    }
+
+    /// Submit runnable tasks as long as there are idle workers.
+    ///
+    /// The tasks are submitted only if the resources they request fit into the capacity
+    /// that is not reserved by the running tasks. If the next task does not fit,
+    /// no other task is submitted before it (see [Status::next_runnable_if]), trading
+    /// some idle capacity for not starving large tasks.
+    ///
+    /// Tasks whose condition does not hold are skipped instead, or blocked if they have
+    /// no condition and some of their dependencies failed. Manual tasks are set
+    /// to wait for an approval, see [Self::submit_approved].
     pub fn submit_runnable(&mut self) -> Result<()> {
-        while let Some(task_id) = self.status.next_runnable() {
+        self.submit_approved()?;
+        loop {
+            if self.status.n_in_progress() >= self.workers.len() {
+                break;
+            }
+            let available = self.available();
+            let fits = |id| match self.tasks.get(&id) {
+                Some(task) => request(task, self.capacity).fits(available),
+                None => true,
+            };
+            let Some(task_id) = self.status.next_runnable_if(fits) else {
+                break;
+            };
             let Some(task) = self.tasks.remove(&task_id) else {
                 return Err(io::Error::other("Inconsistent run status"))?;
             };
-            self.sender.send(WorkInput::Task(task_id, task))?
+            if !self.should_run(&task) {
+                let has_condition = task.options().is_some_and(|o| o.condition.is_some());
+                if !has_condition && self.status.any_failed_or_blocked(task.depends()) {
+                    println!("Skipping {task}, dependency failed");
+                    self.status.block(task_id);
+                } else {
+                    println!("Skipping {task}");
+                    self.status.skip(task_id);
+                }
+                continue;
+            }
+            if !(self.manual & TaskIds::from(task_id)).is_empty() {
+                println!(
+                    "Waiting for approval of {task}, approve with `runr approve {} '{}'`",
+                    self.config.run_id(),
+                    task.name()
+                );
+                self.status.hold(task_id);
+                self.waiting_since.insert(task_id, Instant::now());
+                self.tasks.insert(task_id, task);
+                continue;
+            }
+            self.submit(task_id, task)?;
+        }
+        Ok(())
+    }
+
+    fn submit(&mut self, task_id: TaskId, task: Task) -> Result<()> {
+        let started = (task.name().to_string(), Instant::now());
+        self.started.insert(task_id, started);
+        self.reserved.insert(task_id, request(&task, self.capacity));
+        Ok(self.sender.send(WorkInput::Task(task_id, Box::new(task)))?)
+    }
+
+    /// Submit the approved manual tasks as long as there are idle workers
+    /// and skip the ones whose `approval_timeout` has expired.
+    fn submit_approved(&mut self) -> Result<()> {
+        if self.approvals.is_none() {
+            return Ok(());
+        }
+        for task_id in self.status.pending().ids() {
+            let Some(task) = self.tasks.get(&task_id) else {
+                return Err(io::Error::other("Inconsistent run status"))?;
+            };
+            if self
+                .approvals
+                .as_ref()
+                .is_some_and(|a| a.is_approved(task.name()))
+            {
+                let fits = request(task, self.capacity).fits(self.available());
+                if self.status.n_in_progress() >= self.workers.len() || !fits {
+                    continue;
+                }
+                let task = self.tasks.remove(&task_id).expect("task exists");
+                println!("{task} approved");
+                self.status.release(task_id);
+                self.submit(task_id, task)?;
+                continue;
+            }
+            let approval_timeout = task.options().and_then(|o| o.approval_timeout);
+            let waited = self.waiting_since.get(&task_id).map(Instant::elapsed);
+            if approval_timeout.zip(waited).is_some_and(|(t, w)| w >= t) {
+                println!("Approval of {task} expired, skipping");
+                self.status.skip(task_id);
+            }
         }
         Ok(())
     }
 
-    /// Start the run
+    /// Capacity that is not reserved by the running tasks.
+    fn available(&self) -> Resources {
+        let reserved = self
+            .reserved
+            .values()
+            .fold(Resources::default(), |a, b| a + *b);
+        self.capacity - reserved
+    }
+
+    /// Evaluate the condition of the task, defaults to all dependencies succeeding.
+    fn should_run(&self, task: &Task) -> bool {
+        let deps = task.depends();
+        let deps_succeeded = self.status.all_succeeded(deps);
+        let Some(options) = task.options() else {
+            return deps_succeeded;
+        };
+        let Some(condition) = &options.condition else {
+            return deps_succeeded;
+        };
+        let ctx = Context {
+            git_ref: self.config.git_ref(),
+            commit: self.config.commit(),
+            env: &options.env,
+            deps_succeeded,
+            deps_failed: self.status.any_failed(deps),
+        };
+        condition.eval(&ctx)
+    }
+
+    /// Start the run.
+    ///
+    /// On the first failure, returns immediately unless there are finalizers
+    /// (tasks with `when: always` or `when: on_failure`). In that case, the running containers
+    /// are killed, the unstarted tasks are cancelled and the finalizers are run.
+    /// Timed out tasks are handled as failures. Failures of tasks with `allow_failure`
+    /// are only recorded and the run continues as if they succeeded.
+    ///
+    /// Without `fail_fast`, failures do not stop the run. The tasks that do not depend
+    /// on the failed tasks are run and the dependents are blocked (see [Status::block]).
+    ///
+    /// If the pipeline timeout expires (or the run is cancelled, see [Self::cancel_on_signals]),
+    /// the running tasks are killed and the run is stopped immediately without running
+    /// the finalizers.
+    ///
+    /// Manual tasks wait until they are approved with [crate::approve] (or skipped when
+    /// their `approval_timeout` expires) while the rest of the run continues.
     pub fn start(&mut self) -> Result<()> {
-        while !self.status.is_completed() {
+        let deadline = self.timeout.map(|t| Instant::now() + t);
+        self.watch_approvals(self.manual)?;
+        loop {
             self.submit_runnable()?;
-            match self.check_output()? {
-                WorkOutput::Ok(id) => self.status.complete(id, true),
-                WorkOutput::Failed(id, s) => {
-                    eprintln!("{s}\nKilling containers and exiting.");
-                    self.status.complete(id, false);
+            if self.status.is_completed() {
+                return Ok(());
+            }
+            let poll = match self.status.pending().is_empty() {
+                true => None,
+                false => Some(Instant::now() + APPROVAL_POLL_INTERVAL),
+            };
+            let wait_until = match (deadline, poll) {
+                (Some(d), Some(p)) => Some(d.min(p)),
+                (d, p) => d.or(p),
+            };
+            let Some(output) = self.check_output(wait_until)? else {
+                if deadline.is_none_or(|d| Instant::now() < d) {
+                    continue;
+                }
+                let timeout = self.timeout.unwrap_or_default();
+                eprintln!("Pipeline timed out after {timeout:?}, killing running tasks.");
+                self.timed_out = true;
+                self.kill_running()?;
+                return Ok(());
+            };
+            if let Some(id) = output.task_id() {
+                self.reserved.remove(&id);
+            }
+            let (id, s) = match output {
+                WorkOutput::Ok(id, attempts) => {
+                    self.status.set_attempts(id, attempts);
+                    if let Some((name, started)) = self.started.remove(&id) {
+                        self.history.record(&name, started.elapsed());
+                    }
+                    match self.add_generated(id) {
+                        Ok(()) => {
+                            self.status.complete(id, true);
+                            continue;
+                        }
+                        Err(e) => (
+                            id,
+                            format!("{} generated an invalid pipeline: {e}", self.names[&id]),
+                        ),
+                    }
+                }
+                WorkOutput::Failed(id, s, attempts) => {
+                    self.status.set_attempts(id, attempts);
+                    (id, s)
+                }
+                WorkOutput::TimedOut(id, s, attempts) => {
+                    self.status.time_out(id);
+                    self.status.set_attempts(id, attempts);
+                    (id, s)
+                }
+                WorkOutput::Interrupted(signal) => {
+                    eprintln!("Received signal {signal}, killing running tasks and exiting.");
+                    self.cancelled = true;
+                    self.status.cancel(TaskIds::default());
+                    self.kill_running()?;
                     return Ok(());
                 }
+            };
+            if !(self.allowed_failures & TaskIds::from(id)).is_empty() {
+                eprintln!("{s} (failure allowed)");
+                self.status.tolerate(id);
+                continue;
             }
+            self.status.complete(id, false);
+            // finalizers are run only after all the other tasks
+            if !self.fail_fast || !(self.finalizers & TaskIds::from(id)).is_empty() {
+                eprintln!("{s}");
+                continue;
+            }
+            if self.finalizers.is_empty() {
+                eprintln!("{s}\nKilling containers and exiting.");
+                return Ok(());
+            }
+            eprintln!("{s}\nKilling containers and running finalizers.");
+            self.status.cancel(self.finalizers);
+            self.kill_running()?;
         }
-        Ok(())
     }
 
-    /// Cleanup afterwards, send stop signal and wait for all processes to stop.
-    ///
-    /// In case of a failed job, also kill running containers.
-    pub fn cleanup(mut self) -> Result<usize> {
+    /// Kill the tasks that are currently running, returns the number of killed tasks.
+    fn kill_running(&self) -> Result<usize> {
         let (output_reader, output) = io::pipe()?;
-        let stop_handles: Result<Vec<_>> = self
-            .workers
-            .drain(..)
-            .map(|w| {
-                if let Err(e) = self.sender.send(WorkInput::Stop) {
-                    eprintln!("error with sending stop signal: {e}");
-                };
-                Ok((
-                    w.container_name()
-                        .map(|n| kill_container(n, output.try_clone()?)),
-                    w,
-                ))
-            })
-            .collect();
-        drop(output);
-        let mut killed_sub = 0;
-        for (container, worker) in stop_handles? {
-            if let Some(container) = container {
-                container?.wait()?;
-                killed_sub += 1;
+        let mut processes = vec![];
+        let mut containers = vec![];
+        for worker in self.workers.iter() {
+            let mut running = worker.cancel();
+            let Some(handle) = running.take() else {
+                continue;
+            };
+            let is_process = handle.is_process();
+            let kill = handle.kill(output.try_clone()?)?;
+            // processes are not reaped before they are killed, see [Task::run]
+            match is_process {
+                true => processes.push((kill, running)),
+                false => containers.push(kill),
             }
-            worker.join();
+        }
+        drop(output);
+        let n_killed = processes.len() + containers.len();
+        for (mut kill, running) in processes {
+            kill.wait()?;
+            drop(running);
+        }
+        for mut kill in containers {
+            kill.wait()?;
         }
         for line in BufReader::new(output_reader).lines() {
             println!("{}", line?);
         }
+        Ok(n_killed)
+    }
+
+    /// Cleanup afterwards, send stop signal and wait for all processes to stop.
+    ///
+    /// In case of a failed job, also kill running tasks.
+    pub fn cleanup(mut self) -> Result<usize> {
+        for _ in self.workers.iter() {
+            if let Err(e) = self.sender.send(WorkInput::Stop) {
+                eprintln!("error with sending stop signal: {e}");
+            };
+        }
+        let killed_sub = self.kill_running()?;
+        self.workers.drain(..).for_each(Worker::join);
+        // the run is done, failing to remove the approvals should not fail it
+        if let Some(approvals) = self.approvals.take()
+            && let Err(e) = approvals.remove()
+        {
+            eprintln!("error with removing the approvals: {e}");
+        }
+        // the durations only help scheduling, failing to save them should not fail the run
+        if let Err(e) = self.history.save() {
+            eprintln!("error with saving the task durations: {e}");
+        }
         Ok(killed_sub)
     }
 }
 
+/// Resources requested by the task, limited to the capacity so that the task is run
+/// eventually (generated tasks are not checked against the capacity).
+fn request(task: &Task, capacity: Resources) -> Resources {
+    let request = task.options().map(|o| o.resources()).unwrap_or_default();
+    request.min(capacity)
+}
+
+/// Kill the running tasks without waiting for the containers to stop.
+fn kill_now(running: Vec<Arc<Mutex<Option<Running>>>>) {
+    for running in running {
+        let mut guard = running.lock().unwrap();
+        let Some(running) = guard.take() else {
+            continue;
+        };
+        // processes are locked until killed, see [Task::run]
+        let is_process = running.is_process();
+        if !is_process {
+            drop(guard);
+        }
+        // the output of the kill commands is discarded
+        let killed = io::pipe().map_err(Error::from);
+        let killed = killed.and_then(|(_, output)| {
+            let mut kill = running.kill(output)?;
+            if is_process {
+                kill.wait()?;
+            }
+            Ok(())
+        });
+        if let Err(e) = killed {
+            eprintln!("unable to kill task: {e}");
+        }
+    }
+}
+
 impl fmt::Display for Run {
+    /// Status of the run, the tolerated failures and the tasks that needed
+    /// more than one attempt.
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        write!(f, "{}", self.status)
+        write!(f, "{}", self.status)?;
+        for id in self.status.tolerated().ids() {
+            write!(f, "\n{} failed (allowed)", self.names[&id])?;
+        }
+        for (id, attempts) in self.status.retried() {
+            write!(f, "\n{} needed {attempts} attempts", self.names[id])?;
+        }
+        Ok(())
     }
 }
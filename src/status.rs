@@ -1,12 +1,30 @@
 use crate::pipeline::{TaskId, TaskIds};
+use std::cmp::Reverse;
+use std::collections::{BTreeMap, HashMap};
 use std::fmt;
 
 #[derive(Debug)]
 pub struct Status {
     new: Vec<(TaskId, TaskIds)>,
     in_progress: TaskIds,
+    /// Manual tasks waiting for an approval, these are not included in `in_progress`.
+    pending: TaskIds,
     completed: TaskIds,
     failures: TaskIds,
+    skipped: TaskIds,
+    /// Tasks skipped because some of their dependencies failed (or were blocked themselves),
+    /// these are not included in `skipped`.
+    blocked: TaskIds,
+    timed_out: TaskIds,
+    /// Failed tasks with `allow_failure`, these are not included in `failures`.
+    tolerated: TaskIds,
+    /// Number of attempts of the tasks that were retried.
+    attempts: BTreeMap<TaskId, u32>,
+    /// Expected costs (eg. durations) and priorities of the tasks, see [Self::set_weight].
+    weights: HashMap<TaskId, (u64, i32)>,
+    /// Ranks of the unstarted tasks (see [Self::ranks]), cleared when the tasks,
+    /// their dependencies or weights change.
+    ranks: Option<HashMap<TaskId, (i32, u64)>>,
 }
 
 impl Status {
@@ -20,9 +38,118 @@ impl Status {
         }
     }
 
+    /// Set the given `task_id` to be skipped, which also counts as completed.
+    pub fn skip(&mut self, task_id: TaskId) {
+        let ids = TaskIds::from(task_id);
+        self.in_progress &= !ids;
+        self.pending &= !ids;
+        self.completed |= ids;
+        self.skipped |= ids;
+    }
+
+    /// Set the given `task_id` to be skipped because some of its dependencies failed,
+    /// which also counts as completed.
+    pub fn block(&mut self, task_id: TaskId) {
+        let ids = TaskIds::from(task_id);
+        self.in_progress &= !ids;
+        self.completed |= ids;
+        self.blocked |= ids;
+    }
+
+    /// Check if any of the given tasks failed or was blocked by a failure.
+    pub fn any_failed_or_blocked(&self, task_ids: TaskIds) -> bool {
+        !(task_ids & (self.failures | self.blocked)).is_empty()
+    }
+
+    /// Set the runnable `task_id` to wait for an approval.
+    pub fn hold(&mut self, task_id: TaskId) {
+        let ids = TaskIds::from(task_id);
+        self.in_progress &= !ids;
+        self.pending |= ids;
+    }
+
+    /// Set the approved `task_id` to be in progress.
+    pub fn release(&mut self, task_id: TaskId) {
+        let ids = TaskIds::from(task_id);
+        self.pending &= !ids;
+        self.in_progress |= ids;
+    }
+
+    /// Manual tasks waiting for an approval.
+    pub fn pending(&self) -> TaskIds {
+        self.pending
+    }
+
+    /// Record that the given `task_id` timed out.
+    /// The task must still be completed (or tolerated) separately.
+    pub fn time_out(&mut self, task_id: TaskId) {
+        self.timed_out |= TaskIds::from(task_id);
+    }
+
+    /// Check if any task timed out without the failure being allowed.
+    pub fn any_timed_out(&self) -> bool {
+        !(self.timed_out & !self.tolerated).is_empty()
+    }
+
+    /// Set the given `task_id` to be failed with the failure allowed, which counts
+    /// as succeeded for the dependents and for [Self::is_succeeded].
+    pub fn tolerate(&mut self, task_id: TaskId) {
+        self.complete(task_id, true);
+        self.tolerated |= TaskIds::from(task_id);
+    }
+
+    /// Tasks that failed with the failure allowed.
+    pub fn tolerated(&self) -> TaskIds {
+        self.tolerated
+    }
+
+    /// Record the number of attempts the task needed.
+    pub fn set_attempts(&mut self, task_id: TaskId, attempts: u32) {
+        if attempts > 1 {
+            self.attempts.insert(task_id, attempts);
+        }
+    }
+
+    /// Tasks that needed more than one attempt, with the number of attempts.
+    pub fn retried(&self) -> impl Iterator<Item = (&TaskId, &u32)> {
+        self.attempts.iter()
+    }
+
+    /// Skip all the unstarted (including pending) tasks except the ones in `keep`.
+    pub fn cancel(&mut self, keep: TaskIds) {
+        for id in (self.pending & !keep).ids() {
+            self.skip(id);
+        }
+        let (kept, cancelled) = self
+            .new
+            .drain(..)
+            .partition(|(id, _)| !(TaskIds::from(*id) & keep).is_empty());
+        self.new = kept;
+        self.ranks = None;
+        for (id, _) in cancelled {
+            self.skip(id);
+        }
+    }
+
+    /// Number of tasks in progress.
+    pub fn n_in_progress(&self) -> usize {
+        self.in_progress.ids().count()
+    }
+
+    /// Check if all of the given tasks succeeded (ie. none of them failed or were skipped).
+    pub fn all_succeeded(&self, task_ids: TaskIds) -> bool {
+        let succeeded = self.completed & !self.failures & !self.skipped & !self.blocked;
+        (task_ids & !succeeded).is_empty()
+    }
+
+    /// Check if any of the given tasks failed.
+    pub fn any_failed(&self, task_ids: TaskIds) -> bool {
+        !(task_ids & self.failures).is_empty()
+    }
+
     /// Check if the entire run is completed.
     pub fn is_completed(&self) -> bool {
-        self.new.is_empty() && self.in_progress.is_empty()
+        self.new.is_empty() && self.in_progress.is_empty() && self.pending.is_empty()
     }
 
     pub fn is_succeeded(&self) -> bool {
@@ -35,30 +162,142 @@ impl Status {
         Self {
             new: deps,
             in_progress: TaskIds::default(),
+            pending: TaskIds::default(),
             completed: TaskIds::default(),
             failures: TaskIds::default(),
+            skipped: TaskIds::default(),
+            blocked: TaskIds::default(),
+            timed_out: TaskIds::default(),
+            tolerated: TaskIds::default(),
+            attempts: BTreeMap::new(),
+            weights: HashMap::new(),
+            ranks: None,
         }
     }
 
-    /// Query for a next runnable task (ie. a task that has all of its dependencies completed).
+    /// Add new tasks (with their dependencies) to the run, eg. ones generated by a task.
+    pub fn add(&mut self, deps: impl IntoIterator<Item = (TaskId, TaskIds)>) {
+        self.new.extend(deps);
+        self.ranks = None;
+    }
+
+    /// Add `deps` to the dependencies of the unstarted tasks in `task_ids`.
+    pub fn add_depends(&mut self, task_ids: TaskIds, deps: TaskIds) {
+        self.new
+            .iter_mut()
+            .filter(|(id, _)| !(TaskIds::from(*id) & task_ids).is_empty())
+            .for_each(|(_, d)| *d |= deps);
+        self.ranks = None;
+    }
+
+    /// Set the expected cost (eg. duration) and the priority of the task for scheduling,
+    /// see [Self::next_runnable_if]. Tasks default to a cost of 1 and a priority of 0.
+    pub fn set_weight(&mut self, task_id: TaskId, cost: u64, priority: i32) {
+        self.weights.insert(task_id, (cost, priority));
+        self.ranks = None;
+    }
+
+    /// Query for a next runnable task regardless of resources, see [Self::next_runnable_if].
+    #[cfg(test)]
     pub fn next_runnable(&mut self) -> Option<TaskId> {
+        self.next_runnable_if(|_| true)
+    }
+
+    /// Query for a next runnable task (ie. a task that has all of its dependencies completed),
+    /// if it is accepted by `fits`, eg. it fits into the free resources.
+    ///
+    /// Of the runnable tasks, the one with the highest priority is returned. Priorities are
+    /// inherited by the (transitive) dependencies of the tasks, so that they are not delayed
+    /// by their dependencies. With equal priorities, the task on the longest path of unstarted
+    /// tasks (by their costs) is returned, as the run cannot finish before the path does.
+    ///
+    /// If the task is not accepted, `None` is returned instead of a lower-ranked task,
+    /// so that the task is not starved by the tasks after it.
+    pub fn next_runnable_if(&mut self, fits: impl Fn(TaskId) -> bool) -> Option<TaskId> {
         let incompl = !self.completed;
-        let ind = self.new.iter().position(|t| (t.1 & incompl).is_empty())?;
+        // starting a task does not change the ranks of the others, as it has
+        // no unstarted dependencies
+        if self.ranks.is_none() {
+            self.ranks = Some(self.ranks());
+        }
+        let ranks = self.ranks.as_ref().expect("ranks are computed");
+        let (ind, _) = self
+            .new
+            .iter()
+            .enumerate()
+            .filter(|(_, t)| (t.1 & incompl).is_empty())
+            .max_by_key(|(_, (id, _))| (ranks[id], Reverse(*id)))
+            .filter(|(_, (id, _))| fits(*id))?;
         let id = self.new.swap_remove(ind).0;
         self.in_progress |= TaskIds::from(id);
         Some(id)
     }
+
+    /// Inherited priority and the cost of the longest path starting from each unstarted task.
+    fn ranks(&self) -> HashMap<TaskId, (i32, u64)> {
+        let mut dependents: HashMap<TaskId, Vec<TaskId>> = HashMap::new();
+        for (id, deps) in self.new.iter() {
+            for dep in deps.ids() {
+                dependents.entry(dep).or_default().push(*id);
+            }
+        }
+        let mut ranks = HashMap::new();
+        for (id, _) in self.new.iter() {
+            self.rank(*id, &dependents, &mut ranks);
+        }
+        ranks
+    }
+
+    fn rank(
+        &self,
+        task_id: TaskId,
+        dependents: &HashMap<TaskId, Vec<TaskId>>,
+        ranks: &mut HashMap<TaskId, (i32, u64)>,
+    ) -> (i32, u64) {
+        if let Some(rank) = ranks.get(&task_id) {
+            return *rank;
+        }
+        let (cost, priority) = self.weights.get(&task_id).copied().unwrap_or((1, 0));
+        let (mut priority, mut path) = (priority, 0);
+        for dependent in dependents.get(&task_id).into_iter().flatten() {
+            let (p, c) = self.rank(*dependent, dependents, ranks);
+            priority = priority.max(p);
+            path = path.max(c);
+        }
+        let rank = (priority, cost.saturating_add(path));
+        ranks.insert(task_id, rank);
+        rank
+    }
 }
 
 impl fmt::Display for Status {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        if self.new.is_empty() {
+        if !self.new.is_empty() {
             let new_ids: TaskIds = self.new.iter().map(|x| x.0).collect();
             writeln!(f, "Unstarted tasks: {new_ids}")?;
         }
         if !self.in_progress.is_empty() {
             writeln!(f, "Ongoing tasks:   {}", self.in_progress)?;
         }
+        if !self.pending.is_empty() {
+            writeln!(f, "Pending tasks:   {}", self.pending)?;
+        }
+        if !self.skipped.is_empty() {
+            writeln!(f, "Skipped tasks:   {}", self.skipped)?;
+        }
+        if !self.blocked.is_empty() {
+            writeln!(f, "Blocked tasks:   {}", self.blocked)?;
+        }
+        if !self.timed_out.is_empty() {
+            writeln!(f, "Timed out tasks: {}", self.timed_out)?;
+        }
+        if !self.tolerated.is_empty() {
+            writeln!(f, "Allowed fails:   {}", self.tolerated)?;
+        }
+        if !self.attempts.is_empty() {
+            let retried: TaskIds = self.attempts.keys().copied().collect();
+            writeln!(f, "Retried tasks:   {retried}")?;
+        }
         if !self.completed.is_empty() {
             write!(f, "Completed tasks: {}", self.completed)?;
         }
@@ -93,9 +332,10 @@ mod test {
             .collect();
         let mut status = Status::new(tasks);
 
-        // Two tasks can be started (as they have no dependencies).
-        assert_eq!(status.next_runnable(), Some(TaskId::try_from(2).unwrap()));
+        // Two tasks can be started (as they have no dependencies),
+        // 3 first as it is on the longer path (3 -> 0 -> 1).
         assert_eq!(status.next_runnable(), Some(TaskId::try_from(3).unwrap()));
+        assert_eq!(status.next_runnable(), Some(TaskId::try_from(2).unwrap()));
         assert_eq!(status.next_runnable(), None);
         assert!(!status.is_completed());
 
@@ -122,4 +362,289 @@ mod test {
         // done
         assert!(status.is_completed());
     }
+
+    /// Simulate running the tasks with the given costs and dependencies,
+    /// returns the time it takes to run all of them.
+    fn makespan(tasks: &[(u64, &[usize])], n_workers: usize, history: bool) -> u64 {
+        let id = |i: usize| TaskId::try_from(i).unwrap();
+        let deps = tasks
+            .iter()
+            .enumerate()
+            .map(|(i, (_, deps))| (id(i), deps.iter().map(|d| id(*d)).collect()))
+            .collect();
+        let mut status = Status::new(deps);
+        if history {
+            for (i, (cost, _)) in tasks.iter().enumerate() {
+                status.set_weight(id(i), *cost, 0);
+            }
+        }
+        let (mut now, mut running) = (0, vec![]);
+        loop {
+            while running.len() < n_workers
+                && let Some(task_id) = status.next_runnable()
+            {
+                let i = (0..tasks.len()).position(|i| id(i) == task_id).unwrap();
+                let cost = tasks[i].0;
+                running.push((now + cost, task_id));
+            }
+            running.sort();
+            if running.is_empty() {
+                assert!(status.is_completed());
+                return now;
+            }
+            let (end, task_id) = running.remove(0);
+            now = end;
+            status.complete(task_id, true);
+        }
+    }
+
+    #[test]
+    pub fn tasks_on_the_critical_path_are_started_first() {
+        // independent tasks before a chain of four, starting the tasks in order would take
+        // 6 time units with two workers, as the chain would start only after the others
+        let tasks: [(u64, &[usize]); 8] = [
+            (1, &[]),
+            (1, &[]),
+            (1, &[]),
+            (1, &[]),
+            (1, &[]),
+            (1, &[4]),
+            (1, &[5]),
+            (1, &[6]),
+        ];
+        assert_eq!(makespan(&tasks, 2, false), 4);
+
+        // by task count, the paths 0 -> 4 and 1 -> 2 are equally long, but with the
+        // durations known, 4 is started as soon as possible
+        let tasks: [(u64, &[usize]); 5] = [(1, &[]), (1, &[]), (1, &[1]), (1, &[]), (8, &[0])];
+        assert_eq!(makespan(&tasks, 2, false), 10);
+        assert_eq!(makespan(&tasks, 2, true), 9);
+    }
+
+    #[test]
+    pub fn priorities_are_inherited_by_dependencies() {
+        let [first, second, third] = [0, 1, 2].map(|i| TaskId::try_from(i).unwrap());
+        let tasks = vec![
+            (first, TaskIds::default()),
+            (second, TaskIds::from(first)),
+            (third, TaskIds::default()),
+        ];
+        let mut status = Status::new(tasks);
+        status.set_weight(third, 10, 0);
+        status.set_weight(second, 1, 1);
+        // first is run before the longer third, as the prioritized second depends on it
+        assert_eq!(status.next_runnable(), Some(first));
+        assert_eq!(status.next_runnable(), Some(third));
+    }
+
+    #[test]
+    pub fn skipped_tasks_complete_but_do_not_succeed() {
+        let ids = |ids: &[usize]| -> TaskIds {
+            ids.iter().map(|i| TaskId::try_from(*i).unwrap()).collect()
+        };
+        let tasks = vec![
+            (TaskId::try_from(0).unwrap(), ids(&[])),
+            (TaskId::try_from(1).unwrap(), ids(&[])),
+            (TaskId::try_from(2).unwrap(), ids(&[0, 1])),
+        ];
+        let mut status = Status::new(tasks);
+        status.next_runnable();
+        status.next_runnable();
+        status.skip(TaskId::try_from(0).unwrap());
+        status.complete(TaskId::try_from(1).unwrap(), false);
+
+        assert!(!status.all_succeeded(ids(&[0])));
+        assert!(!status.any_failed(ids(&[0])));
+        assert!(status.any_failed(ids(&[0, 1])));
+        assert_eq!(status.next_runnable(), Some(TaskId::try_from(2).unwrap()));
+        status.skip(TaskId::try_from(2).unwrap());
+        assert!(status.is_completed());
+    }
+
+    #[test]
+    pub fn dependents_of_failures_are_blocked() {
+        let [first, second, third] = [0, 1, 2].map(|i| TaskId::try_from(i).unwrap());
+        let tasks = vec![
+            (first, TaskIds::default()),
+            (second, TaskIds::from(first)),
+            (third, TaskIds::from(second)),
+        ];
+        let mut status = Status::new(tasks);
+        assert_eq!(status.next_runnable(), Some(first));
+        status.complete(first, false);
+        assert_eq!(status.next_runnable(), Some(second));
+        assert!(status.any_failed_or_blocked(TaskIds::from(first)));
+        status.block(second);
+        assert!(!status.any_failed(TaskIds::from(second)));
+        assert!(status.any_failed_or_blocked(TaskIds::from(second)));
+        assert!(!status.all_succeeded(TaskIds::from(second)));
+        assert_eq!(status.next_runnable(), Some(third));
+        status.block(third);
+        assert!(status.is_completed());
+        let status = status.to_string();
+        assert!(status.contains("Blocked tasks:   [1,2]"), "{status}");
+        assert!(!status.contains("Skipped tasks"), "{status}");
+    }
+
+    #[test]
+    pub fn cancel_skips_unstarted_tasks() {
+        let ids = |ids: &[usize]| -> TaskIds {
+            ids.iter().map(|i| TaskId::try_from(*i).unwrap()).collect()
+        };
+        let tasks = (0..4)
+            .map(|i| (TaskId::try_from(i).unwrap(), ids(&[])))
+            .collect();
+        let mut status = Status::new(tasks);
+        let started = status.next_runnable().unwrap();
+        status.cancel(ids(&[3]));
+        assert_eq!(status.n_in_progress(), 1);
+        assert_eq!(status.next_runnable(), Some(TaskId::try_from(3).unwrap()));
+        assert_eq!(status.next_runnable(), None);
+        status.complete(started, false);
+        status.complete(TaskId::try_from(3).unwrap(), true);
+        assert!(status.is_completed());
+        assert!(!status.is_succeeded());
+    }
+
+    #[test]
+    pub fn ranks_are_updated_on_changes() {
+        let [first, second, third] = [0, 1, 2].map(|i| TaskId::try_from(i).unwrap());
+        let tasks = [first, second, third].map(|id| (id, TaskIds::default()));
+        let mut status = Status::new(tasks.to_vec());
+        status.set_weight(first, 10, 0);
+        assert_eq!(status.next_runnable(), Some(first));
+        status.set_weight(second, 10, 0);
+        assert_eq!(status.next_runnable(), Some(second));
+
+        let mut status = Status::new(tasks.to_vec());
+        status.set_weight(first, 10, 0);
+        assert_eq!(status.next_runnable(), Some(first));
+        let fourth = TaskId::try_from(3).unwrap();
+        status.add([(fourth, TaskIds::default())]);
+        status.set_weight(fourth, 5, 0);
+        status.add_depends(TaskIds::from(fourth), TaskIds::from(third));
+        assert_eq!(status.next_runnable(), Some(third));
+    }
+
+    #[test]
+    pub fn tasks_that_do_not_fit_are_not_overtaken() {
+        let [big, small, last] = [0, 1, 2].map(|i| TaskId::try_from(i).unwrap());
+        let tasks = vec![
+            (big, TaskIds::default()),
+            (small, TaskIds::default()),
+            (last, TaskIds::from(small)),
+        ];
+        let mut status = Status::new(tasks);
+        status.set_weight(small, 10, 0);
+        assert_eq!(status.next_runnable_if(|id| id != big), Some(small));
+        // the big task waits for resources, so the smaller ones are not started before it
+        assert_eq!(status.next_runnable_if(|id| id != big), None);
+        status.complete(small, true);
+        assert_eq!(status.next_runnable_if(|id| id != big), None);
+        assert_eq!(status.next_runnable_if(|_| true), Some(big));
+        assert_eq!(status.next_runnable_if(|id| id != big), Some(last));
+    }
+
+    #[test]
+    pub fn timed_out_tasks_fail() {
+        let tasks = vec![(TaskId::try_from(0).unwrap(), TaskIds::default())];
+        let mut status = Status::new(tasks);
+        let id = status.next_runnable().unwrap();
+        status.time_out(id);
+        status.complete(id, false);
+        assert!(status.is_completed());
+        assert!(status.any_failed(TaskIds::from(id)));
+        assert!(status.any_timed_out());
+        assert!(status.to_string().contains("Timed out tasks: [0]"));
+
+        let tasks = vec![(id, TaskIds::default())];
+        let mut status = Status::new(tasks);
+        status.next_runnable();
+        status.time_out(id);
+        status.tolerate(id);
+        assert!(!status.any_timed_out());
+    }
+
+    #[test]
+    pub fn only_retried_tasks_are_reported() {
+        let tasks = (0..2)
+            .map(|i| (TaskId::try_from(i).unwrap(), TaskIds::default()))
+            .collect();
+        let mut status = Status::new(tasks);
+        let [first, second] = [(); 2].map(|_| status.next_runnable().unwrap());
+        status.complete(first, true);
+        status.set_attempts(first, 1);
+        status.complete(second, true);
+        status.set_attempts(second, 3);
+        assert_eq!(status.retried().collect::<Vec<_>>(), [(&second, &3)]);
+        assert!(
+            status
+                .to_string()
+                .contains(&format!("Retried tasks:   [{second}]"))
+        );
+    }
+
+    #[test]
+    pub fn tolerated_failures_do_not_fail_the_run() {
+        let first = TaskId::try_from(0).unwrap();
+        let second = TaskId::try_from(1).unwrap();
+        let tasks = vec![(first, TaskIds::default()), (second, TaskIds::from(first))];
+        let mut status = Status::new(tasks);
+        assert_eq!(status.next_runnable(), Some(first));
+        status.time_out(first);
+        status.tolerate(first);
+        assert!(status.all_succeeded(TaskIds::from(first)));
+        assert_eq!(status.next_runnable(), Some(second));
+        status.complete(second, true);
+        assert!(status.is_completed());
+        assert!(status.is_succeeded());
+        assert_eq!(status.tolerated(), TaskIds::from(first));
+        let status = status.to_string();
+        assert!(status.contains("Allowed fails:   [0]"), "{status}");
+        assert!(status.contains("Timed out tasks: [0]"), "{status}");
+    }
+
+    #[test]
+    pub fn pending_tasks_wait_for_release() {
+        let first = TaskId::try_from(0).unwrap();
+        let second = TaskId::try_from(1).unwrap();
+        let tasks = vec![(first, TaskIds::default()), (second, TaskIds::from(first))];
+        let mut status = Status::new(tasks);
+        assert_eq!(status.next_runnable(), Some(first));
+        status.hold(first);
+        assert_eq!(status.n_in_progress(), 0);
+        assert_eq!(status.next_runnable(), None);
+        assert!(!status.is_completed());
+        assert!(status.to_string().contains("Pending tasks:   [0]"));
+
+        status.release(first);
+        assert_eq!(status.pending(), TaskIds::default());
+        assert_eq!(status.n_in_progress(), 1);
+        status.complete(first, true);
+        assert_eq!(status.next_runnable(), Some(second));
+        status.hold(second);
+        status.cancel(TaskIds::default());
+        assert!(status.is_completed());
+        assert!(!status.all_succeeded(TaskIds::from(second)));
+    }
+
+    #[test]
+    pub fn added_tasks_are_run() {
+        let [first, second, finalizer] = [0, 1, 2].map(|i| TaskId::try_from(i).unwrap());
+        let tasks = vec![
+            (first, TaskIds::default()),
+            (finalizer, TaskIds::from(first)),
+        ];
+        let mut status = Status::new(tasks);
+        assert_eq!(status.next_runnable(), Some(first));
+        status.add([(second, TaskIds::from(first))]);
+        status.add_depends(TaskIds::from(finalizer), TaskIds::from(second));
+        status.complete(first, true);
+        assert_eq!(status.next_runnable(), Some(second));
+        assert_eq!(status.next_runnable(), None);
+        status.complete(second, true);
+        assert_eq!(status.next_runnable(), Some(finalizer));
+        status.complete(finalizer, true);
+        assert!(status.is_completed());
+    }
 }
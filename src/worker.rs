@@ -1,52 +1,80 @@
 use crate::config::RunConfig;
-use crate::err::Result;
-use crate::pipeline::{Task, TaskId};
+use crate::err::{Error, Result};
+use crate::pipeline::{Running, Task, TaskId};
 use std::process::ExitStatus;
-use std::sync::{Arc, Mutex, mpsc};
+use std::sync::{Arc, Condvar, Mutex, MutexGuard, mpsc};
 use std::thread;
+use std::time::Duration;
 
 #[derive(Debug)]
 pub enum WorkInput {
-    Task(TaskId, Task),
+    Task(TaskId, Box<Task>),
     Stop,
 }
 
+/// Result of the task and the number of attempts it took.
 #[derive(Debug)]
 pub enum WorkOutput {
-    Ok(TaskId),
-    Failed(TaskId, String),
+    Ok(TaskId, u32),
+    Failed(TaskId, String, u32),
+    TimedOut(TaskId, String, u32),
+    /// The run received the signal (see [crate::run::Run::cancel_on_signals]),
+    /// never sent by the workers.
+    Interrupted(i32),
+}
+
+impl WorkOutput {
+    /// The task the output is for.
+    pub fn task_id(&self) -> Option<TaskId> {
+        match self {
+            WorkOutput::Ok(id, _) | WorkOutput::Failed(id, ..) | WorkOutput::TimedOut(id, ..) => {
+                Some(*id)
+            }
+            WorkOutput::Interrupted(_) => None,
+        }
+    }
+
+    fn is_ok(&self) -> bool {
+        matches!(self, WorkOutput::Ok(..))
+    }
 }
 
 #[derive(Debug)]
 pub struct Worker {
     thread: thread::JoinHandle<()>,
-    container_name: Arc<Mutex<Option<String>>>,
+    running: Arc<Mutex<Option<Running>>>,
+    cancellation: Arc<Cancellation>,
 }
 
 impl Worker {
-    /// Spawn new worker that is able to process tasks
+    /// Spawn new worker that is able to process tasks.
+    ///
+    /// The worker keeps processing tasks until it receives [WorkInput::Stop],
+    /// so that eg. finalizers can be run after a failure.
     pub fn new(
         receiver: Arc<Mutex<mpsc::Receiver<WorkInput>>>,
         sender: mpsc::Sender<WorkOutput>,
         config: Arc<RunConfig>,
     ) -> Worker {
-        let container_name = Arc::new(Mutex::new(None));
-        let in_progress = container_name.clone();
+        let running = Arc::new(Mutex::new(None));
+        let cancellation = Arc::new(Cancellation::default());
+        let in_progress = running.clone();
+        let cancelled = cancellation.clone();
         let thread = thread::spawn(move || {
             loop {
                 let (task_id, task) = match receiver.lock().unwrap().recv().unwrap() {
                     WorkInput::Stop => break,
                     WorkInput::Task(task_id, task) => (task_id, task),
                 };
-                let exit_code = task.run(config.as_ref(), in_progress.clone());
-                if !handle_status(exit_code, &task, task_id, &sender) {
-                    break;
-                }
+                cancelled.reset();
+                let output = run_task(&task, task_id, &config, &in_progress, &cancelled);
+                sender.send(output).unwrap();
             }
         });
         Worker {
             thread,
-            container_name,
+            running,
+            cancellation,
         }
     }
 
@@ -54,38 +82,164 @@ impl Worker {
         self.thread.join().expect("Couldn't join the thread")
     }
 
-    pub fn container_name(&self) -> Option<String> {
-        self.container_name.lock().unwrap().take()
+    /// Handle to the process (or container) of the current task, for killing it
+    /// from another thread.
+    pub fn running(&self) -> Arc<Mutex<Option<Running>>> {
+        self.running.clone()
+    }
+
+    /// Stop retrying the current task (also interrupting the delay before the next attempt)
+    /// and lock the handle to the running process (or container), if any. The process is
+    /// not reaped until the guard is dropped, so the guard should be held until the process
+    /// is killed.
+    pub fn cancel(&self) -> MutexGuard<'_, Option<Running>> {
+        self.cancellation.cancel();
+        self.running.lock().unwrap()
     }
 }
 
-/// Send status back and signal of processing new tasks should be stopped
+/// Cancellation of the current task of a worker.
+#[derive(Debug, Default)]
+struct Cancellation {
+    cancelled: Mutex<bool>,
+    changed: Condvar,
+}
+
+impl Cancellation {
+    fn cancel(&self) {
+        *self.cancelled.lock().unwrap() = true;
+        self.changed.notify_all();
+    }
+
+    fn reset(&self) {
+        *self.cancelled.lock().unwrap() = false;
+    }
+
+    fn is_cancelled(&self) -> bool {
+        *self.cancelled.lock().unwrap()
+    }
+
+    /// Sleep for the `delay` unless cancelled meanwhile, returns `true` if cancelled.
+    fn sleep(&self, delay: Duration) -> bool {
+        let cancelled = self.cancelled.lock().unwrap();
+        let (cancelled, _) = self
+            .changed
+            .wait_timeout_while(cancelled, delay, |c| !*c)
+            .unwrap();
+        *cancelled
+    }
+}
+
+/// Run the task, re-running it on failure as specified by [Task::retry]
+/// unless the worker is cancelled.
+fn run_task(
+    task: &Task,
+    task_id: TaskId,
+    config: &RunConfig,
+    in_progress: &Arc<Mutex<Option<Running>>>,
+    cancelled: &Cancellation,
+) -> WorkOutput {
+    let retry = task.retry();
+    let mut attempt = 1;
+    loop {
+        let exit_status = task.run(config, in_progress.clone());
+        let output = handle_status(exit_status, task, task_id, attempt);
+        if output.is_ok() || attempt > retry.retries || cancelled.is_cancelled() {
+            return output;
+        }
+        let delay = retry.delay(attempt);
+        let n_attempts = retry.retries + 1;
+        task.log(
+            config,
+            format!("attempt {attempt}/{n_attempts} failed, retrying in {delay:?}"),
+        );
+        if cancelled.sleep(delay) {
+            return output;
+        }
+        attempt += 1;
+    }
+}
+
+/// Convert the exit status of the task into [WorkOutput].
 fn handle_status(
     exit_status: Result<ExitStatus>,
     task: &Task,
     task_id: TaskId,
-    sender: &mpsc::Sender<WorkOutput>,
-) -> bool {
+    attempts: u32,
+) -> WorkOutput {
     let code = match exit_status.map(|s| s.code()) {
         Ok(Some(c)) => c,
         Ok(None) => {
-            fail(task_id, format!("{task} terminated unexpectedly"), sender);
-            return false;
+            let msg = format!("{task} terminated unexpectedly");
+            return WorkOutput::Failed(task_id, msg, attempts);
+        }
+        Err(Error::TimedOut(timeout)) => {
+            let msg = format!("{task} timed out after {timeout:?}");
+            return WorkOutput::TimedOut(task_id, msg, attempts);
         }
         Err(e) => {
-            fail(task_id, format!("{task} exited with an error {e}"), sender);
-            return false;
+            let msg = format!("{task} exited with an error {e}");
+            return WorkOutput::Failed(task_id, msg, attempts);
         }
     };
     if code == 0 {
-        sender.send(WorkOutput::Ok(task_id)).unwrap();
-        return true;
+        return WorkOutput::Ok(task_id, attempts);
     }
     let msg = format!("{task} exited with error code {code}");
-    fail(task_id, msg, sender);
-    false
+    WorkOutput::Failed(task_id, msg, attempts)
 }
 
-fn fail(task_id: TaskId, reason: String, sender: &mpsc::Sender<WorkOutput>) {
-    sender.send(WorkOutput::Failed(task_id, reason)).unwrap()
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::config::GitRef;
+    use crate::pipeline::{Retry, TaskOptions};
+    use std::time::Instant;
+    use std::{env, fs};
+
+    #[test]
+    fn cancelling_interrupts_the_retry_delay() {
+        let repo_path = env::temp_dir().join(format!("runr-worker-test-{}", std::process::id()));
+        fs::create_dir_all(&repo_path).unwrap();
+        let git_ref = GitRef::Branch(String::from("main"));
+        let config = RunConfig::new(
+            repo_path.clone(),
+            String::from("repo"),
+            String::from("run"),
+            true,
+            10,
+            git_ref,
+            String::from("commit"),
+        );
+        let options = TaskOptions {
+            retry: Retry {
+                retries: 2,
+                delay: Duration::from_secs(10),
+            },
+            ..Default::default()
+        };
+        let commands = String::from("echo attempt >> attempts\nexit 1");
+        let task = Task::command(
+            String::from("flaky"),
+            commands,
+            None,
+            Default::default(),
+            options,
+        );
+        let in_progress = Arc::new(Mutex::new(None));
+        let cancellation = Arc::new(Cancellation::default());
+        let cancel = cancellation.clone();
+        let canceller = thread::spawn(move || {
+            thread::sleep(Duration::from_millis(500));
+            cancel.cancel();
+        });
+        let start = Instant::now();
+        let output = run_task(&task, TaskId::first(), &config, &in_progress, &cancellation);
+        canceller.join().unwrap();
+        let attempts = fs::read_to_string(repo_path.join("attempts")).unwrap();
+        fs::remove_dir_all(&repo_path).unwrap();
+        assert!(matches!(output, WorkOutput::Failed(_, _, 1)), "{output:?}");
+        assert!(start.elapsed() < Duration::from_secs(5));
+        assert_eq!(attempts, "attempt\n");
+    }
 }
@@ -1,7 +1,20 @@
-use runr::{Config, Pipeline, repo_checkout};
+use runr::{Config, Pipeline, PipelineConfig, approve, repo_checkout};
 
 const DEFAULT_IMAGE: &str = "docker.io/library/debian:latest";
 
+fn pipeline_config(config: &Config, default_image: Option<&str>) -> PipelineConfig {
+    let default_image = default_image.map(String::from);
+    let filename = String::from("runr.yaml");
+    PipelineConfig::new(
+        default_image,
+        config.git_ref(),
+        config.repo_path(),
+        filename,
+        config.builtin_vars(),
+        config.changed_files(),
+    )
+}
+
 #[ignore]
 #[test]
 fn simple_workflow_succeeds() {
@@ -20,10 +33,11 @@ fn simple_workflow_succeeds() {
             echo ending step 1b
           name: step-1b
           depends: ["step-1a"]"#;
-    let pipeline = Pipeline::read_from(&mut yaml.as_bytes(), &None).unwrap();
     let config = Config::from_env();
+    let pipeline_config = pipeline_config(&config, None);
+    let pipeline = Pipeline::read_from(&mut yaml.as_bytes(), &pipeline_config).unwrap();
     repo_checkout(&config).unwrap();
-    let run_config = config.run_config(&pipeline);
+    let run_config = config.run_config(&pipeline).unwrap();
     let mut run = pipeline.run(run_config);
     run.start().unwrap();
     assert!(run.is_completed());
@@ -54,11 +68,11 @@ fn simple_containerized_workflow_succeeds() {
           image: docker.io/library/python:latest
         "#
     );
-    let pipeline =
-        Pipeline::read_from(&mut yaml.as_bytes(), &Some(DEFAULT_IMAGE.to_string())).unwrap();
     let config = Config::from_env();
+    let pipeline_config = pipeline_config(&config, Some(DEFAULT_IMAGE));
+    let pipeline = Pipeline::read_from(&mut yaml.as_bytes(), &pipeline_config).unwrap();
     repo_checkout(&config).unwrap();
-    let run_config = config.run_config(&pipeline);
+    let run_config = config.run_config(&pipeline).unwrap();
     let mut run = pipeline.run(run_config);
     run.start().unwrap();
     assert!(run.is_completed());
@@ -98,10 +112,11 @@ fn fails_work_as_expected() {
             echo ending step 1b
           name: step-1b
           depends: ["step-1a"]"#;
-    let pipeline = Pipeline::read_from(&mut yaml.as_bytes(), &None).unwrap();
     let config = Config::from_env();
+    let pipeline_config = pipeline_config(&config, None);
+    let pipeline = Pipeline::read_from(&mut yaml.as_bytes(), &pipeline_config).unwrap();
     repo_checkout(&config).unwrap();
-    let run_config = config.run_config(&pipeline);
+    let run_config = config.run_config(&pipeline).unwrap();
     let mut run = pipeline.run(run_config);
     run.start().unwrap();
     assert!(!run.is_completed());
@@ -122,10 +137,85 @@ fn last_step_failing_does_not_result_into_completed() {
           name: step-1b
           depends: ["step-1a"]
           "#;
-    let pipeline = Pipeline::read_from(&mut yaml.as_bytes(), &None).unwrap();
     let config = Config::from_env();
+    let pipeline_config = pipeline_config(&config, None);
+    let pipeline = Pipeline::read_from(&mut yaml.as_bytes(), &pipeline_config).unwrap();
+    repo_checkout(&config).unwrap();
+    let run_config = config.run_config(&pipeline).unwrap();
+    let mut run = pipeline.run(run_config);
+    run.start().unwrap();
+    config.cleanup().unwrap();
+    assert!(run.is_completed());
+    assert!(!run.is_succeeded());
+    assert!(run.cleanup().unwrap() == 0);
+}
+
+#[ignore]
+#[test]
+fn false_condition_skips_task_and_dependents() {
+    let yaml = r#"
+        fail_fast: false
+        tasks:
+        - commands: |
+            exit 1
+          name: step-1a
+          if: branch == 'surely-not-this-branch'
+        - commands: |
+            exit 1
+          name: step-1b
+          depends: ["step-1a"]
+        - commands: |
+            exit 0
+          name: step-2
+          depends: ["step-1a"]
+          if: always()
+          "#;
+    let config = Config::from_env();
+    let pipeline_config = pipeline_config(&config, None);
+    let pipeline = Pipeline::read_from(&mut yaml.as_bytes(), &pipeline_config).unwrap();
+    repo_checkout(&config).unwrap();
+    let run_config = config.run_config(&pipeline).unwrap();
+    let mut run = pipeline.run(run_config);
+    run.start().unwrap();
+    config.cleanup().unwrap();
+    assert!(run.is_completed());
+    assert!(run.is_succeeded());
+    assert!(run.cleanup().unwrap() == 0);
+}
+
+#[ignore]
+#[test]
+fn finalizers_are_run_after_failure() {
+    let yaml = r#"
+        n_parallel: 2
+        tasks:
+        - commands: |
+            exit 1
+          name: step-1a
+        - commands: |
+            sleep 5
+          name: step-1b
+          depends: ["step-1a"]
+        - commands: |
+            echo tearing down
+          name: teardown
+          when: always
+        - commands: |
+            echo notifying
+          name: notify
+          when: on_failure
+          depends: ["teardown"]
+        - commands: |
+            exit 1
+          name: never
+          when: on_failure
+          if: success()
+          "#;
+    let config = Config::from_env();
+    let pipeline_config = pipeline_config(&config, None);
+    let pipeline = Pipeline::read_from(&mut yaml.as_bytes(), &pipeline_config).unwrap();
     repo_checkout(&config).unwrap();
-    let run_config = config.run_config(&pipeline);
+    let run_config = config.run_config(&pipeline).unwrap();
     let mut run = pipeline.run(run_config);
     run.start().unwrap();
     config.cleanup().unwrap();
@@ -133,3 +223,430 @@ fn last_step_failing_does_not_result_into_completed() {
     assert!(!run.is_succeeded());
     assert!(run.cleanup().unwrap() == 0);
 }
+
+#[test]
+#[ignore]
+fn task_timeouts_are_reported() {
+    let yaml = r#"
+        fail_fast: false
+        tasks:
+        - commands: sleep 10
+          name: slow
+          timeout: 1s
+        - commands: sleep 10
+          name: tolerated
+          timeout: 1s
+          allow_failure: true
+          "#;
+    let config = Config::from_env();
+    let pipeline_config = pipeline_config(&config, None);
+    let pipeline = Pipeline::read_from(&mut yaml.as_bytes(), &pipeline_config).unwrap();
+    repo_checkout(&config).unwrap();
+    let run_config = config.run_config(&pipeline).unwrap();
+    let mut run = pipeline.run(run_config);
+    run.start().unwrap();
+    config.cleanup().unwrap();
+    assert!(run.is_completed());
+    assert!(!run.is_succeeded());
+    assert!(run.is_timed_out());
+    run.cleanup().unwrap();
+}
+
+#[test]
+#[ignore]
+fn timed_out_tasks_are_killed() {
+    // the commands started by the shell are killed as well
+    let yaml = r#"
+        timeout: 3s
+        tasks:
+        - commands: |
+            sleep 10
+            echo done
+          name: slow
+          timeout: 1s
+        - commands: |
+            echo cleaning up
+          name: teardown
+          when: always
+        - commands: |
+            sleep 10 | cat
+          name: slower
+          when: always
+          depends: [teardown]
+          "#;
+    let config = Config::from_env();
+    let pipeline_config = pipeline_config(&config, None);
+    let pipeline = Pipeline::read_from(&mut yaml.as_bytes(), &pipeline_config).unwrap();
+    repo_checkout(&config).unwrap();
+    let run_config = config.run_config(&pipeline).unwrap();
+    let mut run = pipeline.run(run_config);
+    let start = std::time::Instant::now();
+    run.start().unwrap();
+    assert!(start.elapsed() < std::time::Duration::from_secs(6));
+    config.cleanup().unwrap();
+    assert!(!run.is_completed());
+    assert!(!run.is_succeeded());
+    assert!(run.is_timed_out());
+    run.cleanup().unwrap();
+}
+
+#[test]
+#[ignore]
+fn background_processes_are_killed_when_the_task_exits() {
+    let yaml = r#"
+        tasks:
+        - commands: |
+            (sleep 1; echo leaked > leaked) > /dev/null 2>&1 &
+            echo started
+          name: background
+          "#;
+    let config = Config::from_env();
+    let pipeline_config = pipeline_config(&config, None);
+    let pipeline = Pipeline::read_from(&mut yaml.as_bytes(), &pipeline_config).unwrap();
+    repo_checkout(&config).unwrap();
+    let run_config = config.run_config(&pipeline).unwrap();
+    let mut run = pipeline.run(run_config);
+    run.start().unwrap();
+    assert!(run.is_succeeded());
+    std::thread::sleep(std::time::Duration::from_secs(2));
+    assert!(!config.repo_path().join("leaked").exists());
+    config.cleanup().unwrap();
+    run.cleanup().unwrap();
+}
+
+#[test]
+#[ignore]
+fn flaky_task_is_retried() {
+    let yaml = r#"
+        tasks:
+        - commands: |
+            echo attempt >> attempts
+            test $(wc -l < attempts) -ge 3
+          name: flaky
+          retries: 2
+          retry_delay: 100ms
+        - commands: |
+            exit 1
+          name: broken
+          retries: 1
+          depends: [flaky]
+          "#;
+    let config = Config::from_env();
+    let pipeline_config = pipeline_config(&config, None);
+    let pipeline = Pipeline::read_from(&mut yaml.as_bytes(), &pipeline_config).unwrap();
+    repo_checkout(&config).unwrap();
+    let run_config = config.run_config(&pipeline).unwrap();
+    let mut run = pipeline.run(run_config);
+    run.start().unwrap();
+    config.cleanup().unwrap();
+    assert!(!run.is_succeeded());
+    let report = run.to_string();
+    assert!(report.contains("flaky needed 3 attempts"), "{report}");
+    assert!(report.contains("broken needed 2 attempts"), "{report}");
+    run.cleanup().unwrap();
+}
+
+#[test]
+#[ignore]
+fn allowed_failures_do_not_fail_the_run() {
+    let yaml = r#"
+        tasks:
+        - commands: |
+            exit 1
+          name: nightly
+          allow_failure: true
+        - commands: |
+            echo still running
+          name: dependent
+          depends: [nightly]
+          "#;
+    let config = Config::from_env();
+    let pipeline_config = pipeline_config(&config, None);
+    let pipeline = Pipeline::read_from(&mut yaml.as_bytes(), &pipeline_config).unwrap();
+    repo_checkout(&config).unwrap();
+    let run_config = config.run_config(&pipeline).unwrap();
+    let mut run = pipeline.run(run_config);
+    run.start().unwrap();
+    config.cleanup().unwrap();
+    assert!(run.is_completed());
+    assert!(run.is_succeeded());
+    let report = run.to_string();
+    assert!(report.contains("nightly failed (allowed)"), "{report}");
+    run.cleanup().unwrap();
+}
+
+#[test]
+#[ignore]
+fn shell_and_script_are_used() {
+    let yaml = r#"
+        tasks:
+        - commands: |
+            import sys
+            sys.exit(0 if sys.version_info.major == 3 else 1)
+          name: python
+          shell: python3
+        - script: Cargo.toml
+          name: script
+          shell: grep -q ^name.=..runr.$
+          "#;
+    let config = Config::from_env();
+    let pipeline_config = pipeline_config(&config, None);
+    repo_checkout(&config).unwrap();
+    let pipeline = Pipeline::read_from(&mut yaml.as_bytes(), &pipeline_config).unwrap();
+    let run_config = config.run_config(&pipeline).unwrap();
+    let mut run = pipeline.run(run_config);
+    run.start().unwrap();
+    config.cleanup().unwrap();
+    assert!(run.is_completed());
+    assert!(run.is_succeeded());
+    run.cleanup().unwrap();
+}
+
+#[test]
+#[ignore]
+fn commands_are_run_in_working_dir() {
+    let yaml = r#"
+        tasks:
+        - commands: |
+            test -f lib.rs
+          name: src
+          working_dir: src
+          "#;
+    let config = Config::from_env();
+    let pipeline_config = pipeline_config(&config, None);
+    let pipeline = Pipeline::read_from(&mut yaml.as_bytes(), &pipeline_config).unwrap();
+    repo_checkout(&config).unwrap();
+    let run_config = config.run_config(&pipeline).unwrap();
+    let mut run = pipeline.run(run_config);
+    run.start().unwrap();
+    config.cleanup().unwrap();
+    assert!(run.is_completed());
+    assert!(run.is_succeeded());
+    run.cleanup().unwrap();
+}
+
+#[test]
+#[ignore]
+fn builtin_variables_are_interpolated() {
+    let yaml = r#"
+        tasks:
+        - commands: |
+            test "${runr.ref}" = main
+            test "${runr.commit}" = "$(git rev-parse HEAD)"
+            test -n "${runr.repo}" && test -n "${runr.timestamp}"
+          name: vars
+          "#;
+    let config = Config::from_env();
+    repo_checkout(&config).unwrap();
+    let pipeline_config = pipeline_config(&config, None);
+    let pipeline = Pipeline::read_from(&mut yaml.as_bytes(), &pipeline_config).unwrap();
+    let run_config = config.run_config(&pipeline).unwrap();
+    let mut run = pipeline.run(run_config);
+    run.start().unwrap();
+    config.cleanup().unwrap();
+    assert!(run.is_completed());
+    assert!(run.is_succeeded());
+    run.cleanup().unwrap();
+}
+
+#[test]
+#[ignore]
+fn manual_tasks_wait_for_approval() {
+    let yaml = r#"
+        tasks:
+        - commands: echo deploying
+          name: deploy
+          manual: true
+        - commands: echo releasing
+          name: release
+          manual: true
+          approval_timeout: 1s
+        - commands: echo announcing
+          name: announce
+          depends: [release]
+          "#;
+    let config = Config::from_env();
+    let pipeline_config = pipeline_config(&config, None);
+    let pipeline = Pipeline::read_from(&mut yaml.as_bytes(), &pipeline_config).unwrap();
+    repo_checkout(&config).unwrap();
+    let run_config = config.run_config(&pipeline).unwrap();
+    let run_id = config.run_id();
+    let approver = std::thread::spawn(move || {
+        std::thread::sleep(std::time::Duration::from_secs(2));
+        approve(&run_id, "deploy").unwrap();
+    });
+    let mut run = pipeline.run(run_config);
+    run.start().unwrap();
+    approver.join().unwrap();
+    config.cleanup().unwrap();
+    assert!(run.is_completed());
+    assert!(run.is_succeeded());
+    let report = run.to_string();
+    // release expired before the approval and announce was skipped with it
+    assert!(report.contains("Skipped tasks:   [1,2]"), "{report}");
+    run.cleanup().unwrap();
+    assert!(approve(&config.run_id(), "deploy").is_err());
+}
+
+#[test]
+#[ignore]
+fn generated_tasks_are_run() {
+    let yaml = r#"
+        tasks:
+        - commands: |
+            echo tasks: > generated.yaml
+            for c in a b; do
+              printf -- '- name: test-%s\n  commands: echo testing %s\n' $c $c
+            done >> generated.yaml
+          name: generate
+          generates: generated.yaml
+        - commands: |
+            echo reporting
+          name: report
+          when: always
+          "#;
+    let config = Config::from_env();
+    let pipeline_config = pipeline_config(&config, None);
+    let pipeline = Pipeline::read_from(&mut yaml.as_bytes(), &pipeline_config).unwrap();
+    repo_checkout(&config).unwrap();
+    let run_config = config.run_config(&pipeline).unwrap();
+    let mut run = pipeline.run(run_config);
+    run.start().unwrap();
+    config.cleanup().unwrap();
+    assert!(run.is_completed());
+    assert!(run.is_succeeded());
+    let report = run.to_string();
+    assert!(report.contains("Completed tasks: [0,1,2,3]"), "{report}");
+    run.cleanup().unwrap();
+}
+
+#[test]
+#[ignore]
+fn generated_duplicate_tasks_fail_the_generator() {
+    let yaml = r#"
+        tasks:
+        - commands: |
+            printf -- 'tasks:\n- name: generate\n  commands: echo again\n' > generated.yaml
+          name: generate
+          generates: generated.yaml
+          "#;
+    let config = Config::from_env();
+    let pipeline_config = pipeline_config(&config, None);
+    let pipeline = Pipeline::read_from(&mut yaml.as_bytes(), &pipeline_config).unwrap();
+    repo_checkout(&config).unwrap();
+    let run_config = config.run_config(&pipeline).unwrap();
+    let mut run = pipeline.run(run_config);
+    run.start().unwrap();
+    config.cleanup().unwrap();
+    assert!(run.is_completed());
+    assert!(!run.is_succeeded());
+    run.cleanup().unwrap();
+}
+
+#[ignore]
+#[test]
+fn independent_tasks_are_run_without_fail_fast() {
+    let yaml = r#"
+        n_parallel: 2
+        fail_fast: false
+        tasks:
+        - commands: exit 1
+          name: broken
+        - commands: echo never
+          name: after-broken
+          depends: [broken]
+        - commands: sleep 1
+          name: slow
+        - commands: echo after slow
+          name: after-slow
+          depends: [slow]
+          "#;
+    let config = Config::from_env();
+    let pipeline_config = pipeline_config(&config, None);
+    let pipeline = Pipeline::read_from(&mut yaml.as_bytes(), &pipeline_config).unwrap();
+    repo_checkout(&config).unwrap();
+    let run_config = config.run_config(&pipeline).unwrap();
+    let mut run = pipeline.run(run_config);
+    run.start().unwrap();
+    config.cleanup().unwrap();
+    assert!(run.is_completed());
+    assert!(!run.is_succeeded());
+    let status = run.to_string();
+    assert!(status.contains("Blocked tasks:   [1]"), "{status}");
+    assert!(status.contains("Completed tasks: [0,1,2,3]"), "{status}");
+    run.cleanup().unwrap();
+}
+
+#[ignore]
+#[test]
+fn tasks_are_run_within_the_capacity() {
+    let yaml = r#"
+        n_parallel: 3
+        capacity:
+          cpus: 1
+        tasks:
+        - commands: sleep 1
+          name: first
+        - commands: sleep 1
+          name: second
+        - commands: sleep 1
+          name: half
+          cpus: 0.5
+        - commands: sleep 1
+          name: other-half
+          cpus: 0.5
+          "#;
+    let config = Config::from_env();
+    let pipeline_config = pipeline_config(&config, None);
+    let pipeline = Pipeline::read_from(&mut yaml.as_bytes(), &pipeline_config).unwrap();
+    repo_checkout(&config).unwrap();
+    let run_config = config.run_config(&pipeline).unwrap();
+    let mut run = pipeline.run(run_config);
+    let start = std::time::Instant::now();
+    run.start().unwrap();
+    let elapsed = start.elapsed();
+    config.cleanup().unwrap();
+    assert!(run.is_succeeded());
+    // the full-cpu tasks are run one at a time, the halves together
+    assert!(elapsed >= std::time::Duration::from_secs(3), "{elapsed:?}");
+    assert!(elapsed < std::time::Duration::from_secs(5), "{elapsed:?}");
+    run.cleanup().unwrap();
+}
+
+#[ignore]
+#[test]
+fn signals_cancel_the_run() {
+    let yaml = r#"
+        tasks:
+        - commands: sleep 10 && echo done
+          name: slow
+        - commands: echo never
+          name: after-slow
+          depends: [slow]
+          "#;
+    let config = Config::from_env();
+    let pipeline_config = pipeline_config(&config, None);
+    let pipeline = Pipeline::read_from(&mut yaml.as_bytes(), &pipeline_config).unwrap();
+    repo_checkout(&config).unwrap();
+    let run_config = config.run_config(&pipeline).unwrap();
+    let mut run = pipeline.run(run_config);
+    run.cancel_on_signals().unwrap();
+    let pid = std::process::id().to_string();
+    let signal = std::thread::spawn(move || {
+        std::thread::sleep(std::time::Duration::from_secs(1));
+        std::process::Command::new("kill")
+            .args(["-TERM", &pid])
+            .status()
+            .unwrap();
+    });
+    let start = std::time::Instant::now();
+    run.start().unwrap();
+    signal.join().unwrap();
+    assert!(run.is_cancelled());
+    assert!(!run.is_completed());
+    let status = run.to_string();
+    assert!(status.contains("Skipped tasks:   [1]"), "{status}");
+    run.cleanup().unwrap();
+    assert!(start.elapsed() < std::time::Duration::from_secs(5));
+    config.cleanup().unwrap();
+}